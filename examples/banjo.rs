@@ -489,13 +489,16 @@ pub fn main() {
     composition.extend(&section_a);
     composition.extend(&outro);
 
+    let mut env = Env::new(44100);
     let banjo = SampleSet::from_directory(
         &"samples/banjo",
         &Regex::new(
             r".*/banjo_(?P<note>[A-G]s?)(?P<octave>[0-9])_very-long_forte_normal_truncated.mp3",
         )
         .unwrap(),
-    );
+        &env,
+    )
+    .unwrap();
     let play_banjo = |note: &Note| banjo.play(note.frequency).unwrap() * note.amplitude;
     let play_bass_banjo = |note: &Note| banjo.play(note.frequency / 2.0).unwrap() * note.amplitude;
     let trumpet = SampleSet::from_directory(
@@ -504,12 +507,16 @@ pub fn main() {
             r".*/trumpet_(?P<note>[A-G]s?)(?P<octave>[0-9])_025_pianissimo_normal_truncated.mp3",
         )
         .unwrap(),
-    );
+        &env,
+    )
+    .unwrap();
     let play_trumpet = |note: &Note| trumpet.play(note.frequency).unwrap() * note.amplitude;
     let double_bass = SampleSet::from_directory(
         &"samples/double_bass",
-        &Regex::new(r".*/double-bass_(?P<note>[A-G][s#]?)(?P<octave>[0-9])_025_piano_pizz-normal_truncated.mp3").unwrap()
-    );
+        &Regex::new(r".*/double-bass_(?P<note>[A-G][s#]?)(?P<octave>[0-9])_025_piano_pizz-normal_truncated.mp3").unwrap(),
+        &env,
+    )
+    .unwrap();
     let play_double_bass = |note: &Note| {
         //double_bass.play(note.frequency/4.0).unwrap() * note.amplitude
         let mut pluck: Value<f64> = PluckedString::new(note.frequency / 8.0, 0.09).into();
@@ -531,9 +538,13 @@ pub fn main() {
             -0.1 / 1000.0,
         );
 
-    sig = Reverb::new(sig, 0.9, 0.1, 1000.0, 2.0).into();
+    sig = Reverb::new(sig)
+        .mix(0.1)
+        .predelay(0.1)
+        .damping(1000.0)
+        .decay(2.0)
+        .into();
 
-    let mut env = Env::new(44100);
     let chunk_size = 1024;
     let total_samples = env.sample_rate as usize * target_len;
     for _ in 0..total_samples / chunk_size {
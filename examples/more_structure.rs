@@ -495,11 +495,14 @@ pub fn main() {
         .parse::<usize>()
         .unwrap();
 
+    let mut env = Env::new(44100);
     let banjo = SampleSet::from_directory(
         &"samples/banjo",
         &Regex::new(r".*/banjo_(?P<note>[A-G]s?)(?P<octave>[0-9])_very-long_forte_normal.mp3")
             .unwrap(),
-    );
+        &env,
+    )
+    .unwrap();
     let play_banjo = |note: &Note| banjo.play(note.frequency).unwrap() * note.amplitude;
 
     let swing = 0.0;
@@ -598,11 +601,15 @@ pub fn main() {
     let pad_mix = rng.gen_range(0.0, 0.8);
     let mut sig = ((melody_voice) * 1.0 + (bass * 1.0 + pads * pad_mix) * 0.4) * 1.0;
 
-    sig = Reverb::new(sig, 0.8, 0.1, 1000.0, 3.8).into();
+    sig = Reverb::new(sig)
+        .mix(0.2)
+        .predelay(0.1)
+        .damping(1000.0)
+        .decay(3.8)
+        .into();
 
     //sig = old_timeify(sig, 1.5);
 
-    let mut env = Env::new(44100);
     let chunk_size = 1024;
     let total_samples = env.sample_rate as usize * target_len;
     for _ in 0..total_samples / chunk_size {
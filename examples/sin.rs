@@ -54,7 +54,7 @@ fn vangelis(duration: Duration, frequency: f64, amp: f64) -> Value<f64> {
         .release(4.2)
         .into();
     sig = RLPF::new(sig, 7000.0 * fenv + 100.0, 1.0.into()).into();
-    //sig = Reverb::new(sig, 0.5, 0.1, 4000.0, 4.0).into();
+    //sig = Reverb::new(sig).mix(0.5).predelay(0.1).damping(4000.0).decay(4.0).into();
     sig * env * amp
 }
 
@@ -212,7 +212,12 @@ fn main() {
         .collect();
 
     sig = sig + SimpleSequence::new(Box::new(bewww), &notes, 3);
-    sig = Reverb::new(sig.into(), 0.8, 0.1, 1000.0, 3.8).into();
+    sig = Reverb::new(sig)
+        .mix(0.2)
+        .predelay(0.1)
+        .damping(1000.0)
+        .decay(3.8)
+        .into();
 
     let sig_in: Value<f64> = WaveTableSynth::sin(440.0.into()).into();
     let sig_modulator: Value<f64> = WaveTableSynth::sin(40.0.into()).into();
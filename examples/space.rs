@@ -290,7 +290,12 @@ fn main() {
     .into();
 
     let mut sig = pad + bass_line + top_notes + structure;
-    sig = Reverb::new(sig, 0.8, 0.1, 2000.0, 4.8).into();
+    sig = Reverb::new(sig)
+        .mix(0.2)
+        .predelay(0.1)
+        .damping(2000.0)
+        .decay(4.8)
+        .into();
 
     let mut env = Env::new(44100);
     let len = env::args()
@@ -338,7 +338,12 @@ fn main() {
     .into();
 
     let mut sig = pad * 0.4 + bass * 0.8 + voice_1 * 0.0 + voice_2 * 1.0;
-    sig = Reverb::new(sig, 0.8, 0.1, 1000.0, 6.8).into();
+    sig = Reverb::new(sig)
+        .mix(0.2)
+        .predelay(0.1)
+        .damping(1000.0)
+        .decay(6.8)
+        .into();
 
     let mut env = Env::new(44100);
     let chunk_size = 2048;
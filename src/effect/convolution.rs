@@ -0,0 +1,284 @@
+use std::marker::PhantomData;
+use std::path::Path;
+
+use num::complex::Complex64;
+
+use crate::{
+    oscillator::{
+        decode::{decode_file, DecodedAudio},
+        sampler::SampleSetError,
+    },
+    spectral::Fft,
+    value::{scratch, Frame, Tail, Value, ValueNode},
+    Env,
+};
+
+struct Channel {
+    // Spectrum of each partition of the impulse response
+    filters: Vec<Vec<Complex64>>,
+    // Spectra of the most recent input blocks, newest at `head`
+    history: Vec<Vec<Complex64>>,
+    head: usize,
+    // The previous input block followed by the one being filled
+    block: Vec<f64>,
+    output: Vec<f64>,
+}
+
+// Convolves the input with an impulse response using uniformly partitioned FFT convolution.
+// Output lags the input by one partition, so smaller partitions mean less latency but more
+// work. A mono impulse response is applied to every input channel; a stereo one gives each
+// side its own response.
+pub struct Convolution<'a, T, O> {
+    input: Value<'a, T>,
+    impulse: DecodedAudio,
+    partition_size: usize,
+    mix: f64,
+
+    sample_rate: u32,
+    // Length of the impulse response at `sample_rate`
+    impulse_len: usize,
+    fft: Fft,
+    channels: Vec<Channel>,
+    position: usize,
+    spectrum: Vec<Complex64>,
+    accumulator: Vec<Complex64>,
+    input_buffer: Vec<T>,
+    tail: Tail,
+    _output: PhantomData<O>,
+}
+
+impl<'a, T, O> Convolution<'a, T, O> {
+    pub fn new(input: impl Into<Value<'a, T>>, impulse: DecodedAudio) -> Self {
+        Self {
+            input: input.into(),
+            impulse,
+            partition_size: 512,
+            mix: 1.0,
+
+            sample_rate: 0,
+            impulse_len: 0,
+            fft: Fft::new(1),
+            channels: vec![],
+            position: 0,
+            spectrum: vec![],
+            accumulator: vec![],
+            input_buffer: vec![],
+            tail: Tail::default(),
+            _output: PhantomData,
+        }
+    }
+
+    // Loads the impulse response the same way SampleSet loads samples
+    pub fn from_file(
+        input: impl Into<Value<'a, T>>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, SampleSetError> {
+        let path = path.as_ref();
+        let impulse = decode_file(path).map_err(|e| SampleSetError::from_decode(path, e))?;
+        Ok(Self::new(input, impulse))
+    }
+
+    // Rounded up to a power of two
+    pub fn partition_size(mut self, partition_size: usize) -> Self {
+        self.partition_size = partition_size.max(1).next_power_of_two();
+        self
+    }
+
+    // How much of the convolved signal is in the output, from 0 to 1. The dry signal is
+    // delayed to line up with it.
+    pub fn mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn latency(&self) -> usize {
+        self.partition_size
+    }
+}
+
+impl<'a, T: Frame, O> Convolution<'a, T, O> {
+    fn build(&mut self, sample_rate: u32) {
+        let size = self.partition_size;
+        let impulse = self.impulse.resampled(sample_rate);
+        self.impulse_len = impulse.len();
+        self.fft = Fft::new(size * 2);
+        let count = T::CHANNELS.min(2).max(impulse.channels.len().min(2));
+        let partitions = impulse.len().div_ceil(size).max(1);
+        self.channels = (0..count)
+            .map(|c| {
+                let response = impulse
+                    .channels
+                    .get(c.min(impulse.channels.len().max(1) - 1))
+                    .map_or(&[][..], |r| &r[..]);
+                let filters = (0..partitions)
+                    .map(|p| {
+                        let mut spectrum = vec![Complex64::new(0.0, 0.0); size * 2];
+                        for (i, v) in response.iter().skip(p * size).take(size).enumerate() {
+                            spectrum[i] = Complex64::new(*v, 0.0);
+                        }
+                        self.fft.forward(&mut spectrum);
+                        spectrum
+                    })
+                    .collect();
+                Channel {
+                    filters,
+                    history: vec![vec![Complex64::new(0.0, 0.0); size * 2]; partitions],
+                    head: 0,
+                    block: vec![0.0; size * 2],
+                    output: vec![0.0; size],
+                }
+            })
+            .collect();
+        self.position = 0;
+        self.spectrum = vec![Complex64::new(0.0, 0.0); size * 2];
+        self.accumulator = vec![Complex64::new(0.0, 0.0); size * 2];
+    }
+
+    fn process_block(&mut self) {
+        let size = self.partition_size;
+        for channel in &mut self.channels {
+            for (s, v) in self.spectrum.iter_mut().zip(channel.block.iter()) {
+                *s = Complex64::new(*v, 0.0);
+            }
+            self.fft.forward(&mut self.spectrum);
+            let partitions = channel.history.len();
+            channel.head = (channel.head + 1) % partitions;
+            channel.history[channel.head].copy_from_slice(&self.spectrum);
+
+            for a in self.accumulator.iter_mut() {
+                *a = Complex64::new(0.0, 0.0);
+            }
+            for (p, filter) in channel.filters.iter().enumerate() {
+                let input = &channel.history[(channel.head + partitions - p) % partitions];
+                for ((a, x), h) in self.accumulator.iter_mut().zip(input).zip(filter) {
+                    *a += x * h;
+                }
+            }
+            self.fft.inverse(&mut self.accumulator);
+            // Overlap-save: the first half has wrapped around and is thrown away
+            for (o, a) in channel.output.iter_mut().zip(&self.accumulator[size..]) {
+                *o = a.re;
+            }
+            channel.block.copy_within(size.., 0);
+        }
+    }
+}
+
+impl<'a, T: Frame, O: Frame> ValueNode for Convolution<'a, T, O> {
+    type T = O;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.build(env.sample_rate);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+
+        let size = self.partition_size;
+        for (i, out) in buffer[0..samples].iter_mut().enumerate() {
+            let mut frame = [0.0; 2];
+            for (c, channel) in self.channels.iter_mut().enumerate() {
+                channel.block[size + self.position] =
+                    self.input_buffer[i].channel(c.min(T::CHANNELS - 1));
+                let dry = channel.block[self.position];
+                frame[c] = dry * (1.0 - self.mix) + channel.output[self.position] * self.mix;
+            }
+            *out = O::from_channels(&frame[0..self.channels.len()]);
+            self.position += 1;
+            if self.position == size {
+                self.position = 0;
+                self.process_block();
+            }
+        }
+        let required = self.impulse_len + size;
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], required);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::MultiSample;
+
+    struct Samples(Vec<MultiSample<f64>>, usize);
+
+    impl ValueNode for Samples {
+        type T = MultiSample<f64>;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = self.0.get(self.1).cloned().unwrap_or_default();
+                self.1 += 1;
+            }
+        }
+    }
+
+    fn direct(input: &[f64], impulse: &[f64]) -> Vec<f64> {
+        (0..input.len())
+            .map(|n| {
+                impulse
+                    .iter()
+                    .enumerate()
+                    .take(n + 1)
+                    .map(|(k, h)| h * input[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let len = 400;
+        let left: Vec<f64> = (0..len).map(|n| (0.13 * n as f64).sin()).collect();
+        let right: Vec<f64> = (0..len)
+            .map(|n| ((n * 37) % 11) as f64 / 5.0 - 1.0)
+            .collect();
+        // Longer than three partitions, with a different response on each side
+        let impulse = DecodedAudio {
+            sample_rate: 8000,
+            original_sample_rate: 8000,
+            channels: vec![
+                (0..53)
+                    .map(|k| 0.9f64.powi(k) * if k % 2 == 0 { 1.0 } else { -0.5 })
+                    .collect(),
+                (0..53).map(|k| 1.0 / (k as f64 + 1.0)).collect(),
+            ],
+        };
+        let expected = [
+            direct(&left, &impulse.channels[0]),
+            direct(&right, &impulse.channels[1]),
+        ];
+
+        let input = left
+            .iter()
+            .zip(&right)
+            .map(|(l, r)| MultiSample(*l, *r))
+            .collect();
+        let mut convolution: Value<MultiSample<f64>> =
+            Convolution::<_, MultiSample<f64>>::new(Samples(input, 0), impulse)
+                .partition_size(16)
+                .into();
+        let env = Env::new(8000);
+        let mut output = vec![MultiSample(0.0, 0.0); len + 16];
+        // Blocks that don't line up with the partitions
+        for block in output.chunks_mut(7) {
+            let samples = block.len();
+            convolution.fill_buffer(&env, block, samples);
+        }
+
+        assert!(output[0..16].iter().all(|v| v.0 == 0.0 && v.1 == 0.0));
+        for (n, frame) in output[16..].iter().enumerate() {
+            assert!((frame.0 - expected[0][n]).abs() < 1e-9, "left {}", n);
+            assert!((frame.1 - expected[1][n]).abs() < 1e-9, "right {}", n);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::ops::{Add, Mul, Sub};
+
+use crate::{
+    value::{scratch, Frame, Tail, Value, ValueNode},
+    Env,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DelayInterpolation {
+    Linear,
+    Cubic,
+    // First order allpass. Keeps the high end intact, which matters inside feedback loops,
+    // but smears fast changes to the delay time.
+    AllPass,
+}
+
+// Ring buffer of past samples that can be read back at any delay up to its length
+pub struct DelayLine<T> {
+    buffer: Vec<T>,
+    position: usize,
+}
+
+impl<T: Copy + Default> DelayLine<T> {
+    pub fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![T::default(); len.max(4)],
+            position: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn push(&mut self, sample: T) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+
+    // The sample pushed `delay` pushes ago, so 1 is the most recent one
+    pub fn get(&self, delay: usize) -> T {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - delay % len) % len]
+    }
+}
+
+// Reads a DelayLine at fractional delays. The allpass interpolator has state of its own, so
+// every read position needs its own tap.
+pub struct DelayTap<T> {
+    interpolation: DelayInterpolation,
+    previous: T,
+}
+
+impl<T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> DelayTap<T> {
+    pub fn new(interpolation: DelayInterpolation) -> Self {
+        Self {
+            interpolation,
+            previous: T::default(),
+        }
+    }
+
+    // `delay` is in samples and is clamped to what the line can hold
+    pub fn read(&mut self, line: &DelayLine<T>, delay: f64) -> T {
+        let max = (line.capacity() - 2) as f64;
+        // A NaN delay, say from a modulator gone wrong, reads the shortest delay
+        let delay = if delay.is_nan() { 0.0 } else { delay };
+        match self.interpolation {
+            DelayInterpolation::Linear => {
+                let delay = delay.clamp(1.0, max);
+                let i = delay as usize;
+                let f = T::from(delay - i as f64);
+                let (a, b) = (line.get(i), line.get(i + 1));
+                a + (b - a) * f
+            }
+            DelayInterpolation::Cubic => {
+                let delay = delay.clamp(2.0, max);
+                let i = delay as usize;
+                let f = delay - i as f64;
+                let (xm1, x0, x1, x2) = (
+                    line.get(i - 1),
+                    line.get(i),
+                    line.get(i + 1),
+                    line.get(i + 2),
+                );
+                let c1 = (x1 - xm1) * T::from(0.5);
+                let c2 = xm1 - x0 * T::from(2.5) + x1 * T::from(2.0) - x2 * T::from(0.5);
+                let c3 = (x2 - xm1) * T::from(0.5) + (x0 - x1) * T::from(1.5);
+                let f = T::from(f);
+                ((c3 * f + c2) * f + c1) * f + x0
+            }
+            DelayInterpolation::AllPass => {
+                let delay = delay.clamp(1.0, max);
+                let mut i = delay as usize;
+                let mut f = delay - i as f64;
+                // Fractions near zero put the pole next to the unit circle and ring
+                if f < 0.1 && i > 1 {
+                    i -= 1;
+                    f += 1.0;
+                }
+                let a = T::from((1.0 - f) / (1.0 + f));
+                let out = line.get(i) * a + line.get(i + 1) - self.previous * a;
+                self.previous = out;
+                out
+            }
+        }
+    }
+}
+
+// Anything that can sit in a delay's feedback path, run one sample at a time
+pub trait FeedbackFilter<T> {
+    fn process(&mut self, sample_rate: u32, input: T) -> T;
+}
+
+impl<T, F: FnMut(T) -> T> FeedbackFilter<T> for F {
+    fn process(&mut self, _sample_rate: u32, input: T) -> T {
+        self(input)
+    }
+}
+
+// Gentle 6dB/octave filter, mostly for darkening or thinning out repeats
+pub struct OnePole<T> {
+    cutoff: f64,
+    high_pass: bool,
+    sample_rate: u32,
+    coefficient: f64,
+    state: T,
+}
+
+impl<T: Default> OnePole<T> {
+    fn new(cutoff: f64, high_pass: bool) -> Self {
+        Self {
+            cutoff,
+            high_pass,
+            sample_rate: 0,
+            coefficient: 0.0,
+            state: T::default(),
+        }
+    }
+
+    pub fn low_pass(cutoff: f64) -> Self {
+        Self::new(cutoff, false)
+    }
+
+    pub fn high_pass(cutoff: f64) -> Self {
+        Self::new(cutoff, true)
+    }
+}
+
+impl<T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> FeedbackFilter<T>
+    for OnePole<T>
+{
+    fn process(&mut self, sample_rate: u32, input: T) -> T {
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            self.coefficient =
+                (-2.0 * std::f64::consts::PI * self.cutoff / sample_rate as f64).exp();
+        }
+        self.state = input + (self.state - input) * T::from(self.coefficient);
+        if self.high_pass {
+            input - self.state
+        } else {
+            self.state
+        }
+    }
+}
+
+// Delay whose time, in seconds, can be modulated up to `max_delay`. The output is only the
+// delayed signal.
+pub struct ModulatedDelay<'a, T> {
+    input: Value<'a, T>,
+    delay: Value<'a, f64>,
+    feedback: Value<'a, f64>,
+    max_delay: f64,
+    filter: Option<Box<dyn FeedbackFilter<T> + 'a>>,
+
+    sample_rate: u32,
+    line: DelayLine<T>,
+    tap: DelayTap<T>,
+    input_buffer: Vec<T>,
+    delay_buffer: Vec<f64>,
+    feedback_buffer: Vec<f64>,
+    tail: Tail,
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>>
+    ModulatedDelay<'a, T>
+{
+    pub fn new(
+        input: impl Into<Value<'a, T>>,
+        delay: impl Into<Value<'a, f64>>,
+        max_delay: f64,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            delay: delay.into(),
+            feedback: 0.0.into(),
+            max_delay,
+            filter: None,
+
+            sample_rate: 0,
+            line: DelayLine::new(0),
+            tap: DelayTap::new(DelayInterpolation::Linear),
+            input_buffer: vec![],
+            delay_buffer: vec![],
+            feedback_buffer: vec![],
+            tail: Tail::default(),
+        }
+    }
+
+    pub fn interpolation(mut self, interpolation: DelayInterpolation) -> Self {
+        self.tap = DelayTap::new(interpolation);
+        self
+    }
+
+    pub fn feedback(mut self, feedback: impl Into<Value<'a, f64>>) -> Self {
+        self.feedback = feedback.into();
+        self
+    }
+
+    pub fn feedback_filter(mut self, filter: impl FeedbackFilter<T> + 'a) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> ValueNode
+    for ModulatedDelay<'a, T>
+{
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.line = DelayLine::new((self.max_delay * env.sample_rate as f64) as usize + 4);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        let delay = scratch(&mut self.delay_buffer, samples);
+        let feedback = scratch(&mut self.feedback_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.delay.fill_buffer(env, delay, samples);
+        self.feedback.fill_buffer(env, feedback, samples);
+        for i in 0..samples {
+            let out = self.tap.read(&self.line, delay[i] * env.sample_rate as f64);
+            let returned = match &mut self.filter {
+                Some(filter) => filter.process(env.sample_rate, out),
+                None => out,
+            };
+            self.line.push(input[i] + returned * T::from(feedback[i]));
+            buffer[i] = out;
+        }
+        let required = self.line.capacity();
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], required);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.delay_buffer, max_block_size);
+        scratch(&mut self.feedback_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.delay.prepare(max_block_size);
+        self.feedback.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
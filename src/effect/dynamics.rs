@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+use std::ops::Mul;
+
+use crate::{
+    envelope::{coefficient, peak},
+    spectral::{from_db, to_db},
+    value::{scratch, Frame, Value, ValueNode},
+    Env,
+};
+
+// Gain reduction never goes further than this so the smoothing in dB stays finite
+const MIN_GAIN_DB: f64 = -120.0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Compressor,
+    Expander,
+    Gate,
+}
+
+// Compressor, expander and noise gate. All of the levels are in dB and the times in
+// seconds. Channels are linked, and the level is taken from the sidechain if there is one.
+pub struct Dynamics<'a, T> {
+    input: Value<'a, T>,
+    sidechain: Option<Value<'a, T>>,
+    kind: Kind,
+    threshold: f64,
+    ratio: f64,
+    knee: f64,
+    attack: f64,
+    release: f64,
+    makeup: f64,
+
+    gain: f64,
+    input_buffer: Vec<T>,
+    sidechain_buffer: Vec<T>,
+}
+
+impl<'a, T> Dynamics<'a, T> {
+    fn new(input: impl Into<Value<'a, T>>, kind: Kind, threshold: f64, ratio: f64) -> Self {
+        Self {
+            input: input.into(),
+            sidechain: None,
+            kind,
+            threshold,
+            ratio,
+            knee: 0.0,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 0.0,
+
+            gain: 0.0,
+            input_buffer: vec![],
+            sidechain_buffer: vec![],
+        }
+    }
+
+    // Turns the level down by `ratio` above the threshold
+    pub fn compressor(input: impl Into<Value<'a, T>>) -> Self {
+        Self::new(input, Kind::Compressor, -18.0, 4.0)
+    }
+
+    // Turns the level down by `ratio` below the threshold
+    pub fn expander(input: impl Into<Value<'a, T>>) -> Self {
+        Self::new(input, Kind::Expander, -40.0, 2.0)
+    }
+
+    // Shuts off anything below the threshold
+    pub fn gate(input: impl Into<Value<'a, T>>) -> Self {
+        let mut gate = Self::new(input, Kind::Gate, -50.0, f64::INFINITY);
+        gate.attack = 0.001;
+        gate
+    }
+
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn ratio(mut self, ratio: f64) -> Self {
+        self.ratio = ratio.max(1.0);
+        self
+    }
+
+    pub fn knee(mut self, knee: f64) -> Self {
+        self.knee = knee.max(0.0);
+        self
+    }
+
+    pub fn attack(mut self, attack: f64) -> Self {
+        self.attack = attack;
+        self
+    }
+
+    pub fn release(mut self, release: f64) -> Self {
+        self.release = release;
+        self
+    }
+
+    pub fn makeup(mut self, makeup: f64) -> Self {
+        self.makeup = makeup;
+        self
+    }
+
+    pub fn sidechain(mut self, sidechain: impl Into<Value<'a, T>>) -> Self {
+        self.sidechain = Some(sidechain.into());
+        self
+    }
+
+    // The static curve: how many dB of gain to apply to a signal at `level` dB
+    fn gain_for(&self, level: f64) -> f64 {
+        let over = level - self.threshold;
+        let half_knee = self.knee / 2.0;
+        let gain = match self.kind {
+            Kind::Compressor => {
+                let slope = 1.0 / self.ratio - 1.0;
+                if over <= -half_knee {
+                    0.0
+                } else if over < half_knee {
+                    slope * (over + half_knee).powi(2) / (2.0 * self.knee)
+                } else {
+                    slope * over
+                }
+            }
+            Kind::Expander | Kind::Gate => {
+                let slope = self.ratio - 1.0;
+                if over >= half_knee {
+                    0.0
+                } else if over > -half_knee {
+                    -slope * (over - half_knee).powi(2) / (2.0 * self.knee)
+                } else {
+                    slope * over
+                }
+            }
+        };
+        if gain.is_nan() {
+            MIN_GAIN_DB
+        } else {
+            gain.max(MIN_GAIN_DB)
+        }
+    }
+}
+
+impl<'a, T: Frame + Mul<Output = T> + From<f64>> ValueNode for Dynamics<'a, T> {
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        if let Some(sidechain) = &mut self.sidechain {
+            let sidechain_buffer = scratch(&mut self.sidechain_buffer, samples);
+            sidechain.fill_buffer(env, sidechain_buffer, samples);
+        }
+        let attack = coefficient(self.attack, env.sample_rate);
+        let release = coefficient(self.release, env.sample_rate);
+        for (i, out) in buffer[0..samples].iter_mut().enumerate() {
+            let detected = match self.sidechain {
+                Some(_) => &self.sidechain_buffer[i],
+                None => &self.input_buffer[i],
+            };
+            let target = self.gain_for(to_db(peak(detected)));
+            // Attack is how quickly a compressor clamps down but how quickly a gate opens
+            let attacking = match self.kind {
+                Kind::Compressor => target < self.gain,
+                Kind::Expander | Kind::Gate => target > self.gain,
+            };
+            let c = if attacking { attack } else { release };
+            self.gain = target + (self.gain - target) * c;
+            *out = self.input_buffer[i] * T::from(from_db(self.gain + self.makeup));
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        if let Some(sidechain) = &mut self.sidechain {
+            scratch(&mut self.sidechain_buffer, max_block_size);
+            sidechain.prepare(max_block_size);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.input.is_finished()
+    }
+}
+
+// Brickwall limiter. The input is delayed by the lookahead time so the gain can come down
+// smoothly ahead of a peak instead of clipping it.
+pub struct Limiter<'a, T> {
+    input: Value<'a, T>,
+    ceiling: f64,
+    lookahead: f64,
+    release: f64,
+
+    sample_rate: u32,
+    delay: VecDeque<T>,
+    // Sliding minimum of the gain each sample needs, as (sample number, gain) pairs
+    minimum: VecDeque<(usize, f64)>,
+    smoothed: f64,
+    average: VecDeque<f64>,
+    average_sum: f64,
+    sample: usize,
+    input_buffer: Vec<T>,
+    finished_samples: usize,
+}
+
+impl<'a, T> Limiter<'a, T> {
+    pub fn new(input: impl Into<Value<'a, T>>) -> Self {
+        Self {
+            input: input.into(),
+            ceiling: -0.3,
+            lookahead: 0.005,
+            release: 0.05,
+
+            sample_rate: 0,
+            delay: VecDeque::new(),
+            minimum: VecDeque::new(),
+            smoothed: 1.0,
+            average: VecDeque::new(),
+            average_sum: 0.0,
+            sample: 0,
+            input_buffer: vec![],
+            finished_samples: 0,
+        }
+    }
+
+    // Highest level, in dB, that will make it to the output
+    pub fn ceiling(mut self, ceiling: f64) -> Self {
+        self.ceiling = ceiling;
+        self
+    }
+
+    pub fn lookahead(mut self, lookahead: f64) -> Self {
+        self.lookahead = lookahead;
+        self
+    }
+
+    pub fn release(mut self, release: f64) -> Self {
+        self.release = release;
+        self
+    }
+}
+
+impl<'a, T: Frame + Mul<Output = T> + From<f64>> ValueNode for Limiter<'a, T> {
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        // The gain is worked out over the lookahead plus the sample being output, so the
+        // delay comes to exactly the lookahead
+        let window = (self.lookahead.max(0.0) * env.sample_rate as f64) as usize + 1;
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.delay = VecDeque::with_capacity(window);
+            self.delay.resize_with(window - 1, T::default);
+            self.minimum = VecDeque::with_capacity(window + 1);
+            self.average = VecDeque::with_capacity(window);
+            self.average.resize(window, 1.0);
+            self.average_sum = window as f64;
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        let ceiling = from_db(self.ceiling);
+        let release = coefficient(self.release, env.sample_rate);
+        for i in 0..samples {
+            let level = peak(&input[i]);
+            let required = if level > ceiling {
+                ceiling / level
+            } else {
+                1.0
+            };
+
+            while self.minimum.back().is_some_and(|(_, g)| *g >= required) {
+                self.minimum.pop_back();
+            }
+            let now = self.sample;
+            self.minimum.push_back((now, required));
+            while self.minimum.front().is_some_and(|(n, _)| n + window <= now) {
+                self.minimum.pop_front();
+            }
+            let held = self.minimum.front().map_or(1.0, |(_, g)| *g);
+
+            // Clamp down straight away but recover slowly. Never rising above the held gain
+            // keeps every sample in the window under the ceiling.
+            self.smoothed = if held < self.smoothed {
+                held
+            } else {
+                held + (self.smoothed - held) * release
+            };
+
+            // Averaging over the window turns the step down into a ramp that finishes just as
+            // the peak comes out of the delay
+            self.average_sum += self.smoothed - self.average.pop_front().unwrap_or(1.0);
+            self.average.push_back(self.smoothed);
+            self.sample += 1;
+            if self.sample.is_multiple_of(window) {
+                self.average_sum = self.average.iter().sum();
+            }
+            let gain = (self.average_sum / window as f64).min(1.0);
+
+            self.delay.push_back(input[i]);
+            let delayed = self.delay.pop_front().unwrap_or_default();
+            buffer[i] = delayed * T::from(gain);
+        }
+        if self.input.is_finished() {
+            self.finished_samples += samples;
+        } else {
+            self.finished_samples = 0;
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.input.is_finished() && self.finished_samples >= self.delay.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Samples(Vec<f64>, usize);
+
+    impl ValueNode for Samples {
+        type T = f64;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = self.0.get(self.1).cloned().unwrap_or(0.0);
+                self.1 += 1;
+            }
+        }
+    }
+
+    fn render(value: impl Into<Value<'static, f64>>, len: usize) -> Vec<f64> {
+        let mut value = value.into();
+        let env = Env::new(1024);
+        let mut output = vec![0.0; len];
+        for block in output.chunks_mut(100) {
+            let samples = block.len();
+            value.fill_buffer(&env, block, samples);
+        }
+        output
+    }
+
+    // A quiet sine with loud spikes dropped in without warning
+    fn spiky(len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|n| match n % 397 {
+                200 => 4.0,
+                201 => -3.0,
+                250..=255 => 2.5,
+                _ => 0.3 * (0.05 * n as f64).sin(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limiter_keeps_under_the_ceiling() {
+        let input = spiky(5000);
+        let limiter = Limiter::new(Samples(input, 0))
+            .ceiling(-1.0)
+            .lookahead(8.0 / 1024.0);
+        let ceiling = from_db(-1.0);
+        for v in render(limiter, 5000) {
+            assert!(v.abs() <= ceiling + 1e-12, "{}", v);
+        }
+    }
+
+    #[test]
+    fn limiter_delays_by_the_lookahead() {
+        let input: Vec<_> = (0..1000).map(|n| 0.5 * (0.1 * n as f64).sin()).collect();
+        let limiter = Limiter::new(Samples(input.clone(), 0))
+            .ceiling(0.0)
+            .lookahead(8.0 / 1024.0);
+        let output = render(limiter, 1000);
+        assert!(output[0..8].iter().all(|v| *v == 0.0));
+        for (a, b) in output[8..].iter().zip(&input) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
+    // Steady state output for a constant input of `level`
+    fn settled(dynamics: Dynamics<'static, f64>) -> f64 {
+        *render(dynamics, 4096).last().unwrap()
+    }
+
+    #[test]
+    fn compressor_follows_the_static_curve() {
+        let level = 0.5;
+        let over = to_db(level) + 18.0;
+        let expected = level * from_db((1.0 / 4.0 - 1.0) * over);
+        let hard = Dynamics::compressor(level).threshold(-18.0).ratio(4.0);
+        assert!((settled(hard) - expected).abs() < 1e-9);
+
+        // Inside a 6dB knee centred 2dB below the level
+        let over = to_db(level) + 8.0;
+        let expected = level * from_db(-0.75 * (over + 3.0).powi(2) / 12.0);
+        let soft = Dynamics::compressor(level)
+            .threshold(-8.0)
+            .ratio(4.0)
+            .knee(6.0);
+        assert!((settled(soft) - expected).abs() < 1e-9);
+
+        // Below the knee nothing changes
+        let quiet = Dynamics::compressor(level).threshold(0.0).knee(6.0);
+        assert!((settled(quiet) - level).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gate_mutes_below_the_threshold() {
+        let quiet = settled(Dynamics::gate(0.001).threshold(-50.0));
+        assert!(quiet.abs() < 1e-8, "{}", quiet);
+        let loud = settled(Dynamics::gate(0.1).threshold(-50.0));
+        assert!((loud - 0.1).abs() < 1e-9);
+    }
+}
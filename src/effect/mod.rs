@@ -1,28 +1,43 @@
-use num::{Num, One, Zero};
+mod convolution;
+mod delay;
+mod dynamics;
+mod modulation;
+mod reverb;
+pub use convolution::*;
+pub use delay::*;
+pub use dynamics::*;
+pub use modulation::*;
+pub use reverb::*;
+
+use num::Num;
 use std::clone::Clone;
 use std::collections::VecDeque;
-use std::ops::{Add, Mul, Neg, Sub};
 
 use crate::{
-    filter::{AllPass, TrapezoidSVF, RLPF},
+    filter::TrapezoidSVF,
     oscillator::BrownianNoise,
-    value::{CacheValue, Value, ValueConverter, ValueNode},
+    value::{scratch, Value, ValueNode},
     Env,
 };
 
 pub struct Delay<'a, T> {
     input: Value<'a, T>,
+    delay: f64,
+    sample_rate: u32,
     buffer: VecDeque<T>,
+    input_buffer: Vec<T>,
+    finished_samples: usize,
 }
 
 impl<'a, T: Default> Delay<'a, T> {
     pub fn new(input: impl Into<Value<'a, T>>, delay: f64) -> Self {
-        let capacity = (44100.0 * delay) as usize;
-        let mut buffer = VecDeque::with_capacity(capacity);
-        buffer.resize_with(capacity, || T::default());
         Delay {
             input: input.into(),
-            buffer: buffer,
+            delay,
+            sample_rate: 0,
+            buffer: VecDeque::new(),
+            input_buffer: vec![],
+            finished_samples: 0,
         }
     }
 }
@@ -30,81 +45,31 @@ impl<'a, T: Default> Delay<'a, T> {
 impl<'a, T: Default + Clone> ValueNode for Delay<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            let len = (env.sample_rate as f64 * self.delay) as usize;
+            self.buffer.resize_with(len, T::default);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
         for i in 0..samples {
             self.buffer.push_back(input[i].clone());
             buffer[i] = self.buffer.pop_front().unwrap_or_else(|| T::default());
         }
-    }
-}
-
-pub struct Reverb<'a, T> {
-    output: Value<'a, T>,
-}
-
-impl<
-        'a,
-        T: Copy
-            + Default
-            + Zero
-            + One
-            + Into<Value<'a, T>>
-            + From<f64>
-            + Neg<Output = T>
-            + Add<Output = T>
-            + Mul<Output = T>
-            + Sub<Output = T>
-            + 'a,
-    > Reverb<'a, T>
-{
-    pub fn new(
-        input: impl Into<Value<'a, T>>,
-        mix: f64,
-        predelay: f64,
-        lpf: f64,
-        revtime: f64,
-    ) -> Self {
-        let dry = CacheValue::new(input);
-        let mut temp: Value<T> = Delay::new(dry.clone(), predelay).into();
-        let mut wet: Value<T> = T::zero().into();
-        let things = vec![
-            0.038045169615104804,
-            0.02999076016847762,
-            0.04963873923379772,
-            0.04368894979626656,
-            0.007460425959828037,
-            0.02817080130412364,
-            0.00657126832222354,
-            0.04779429369666802,
-            0.004010513054838128,
-            0.01541601071664956,
-            0.011602441530870984,
-            0.0012122872292874213,
-            0.025404225677194647,
-            0.0017341472693168261,
-            0.01003645759720834,
-            0.04604357296027947,
-        ];
-
-        for r in things {
-            let ltemp = AllPass::new(temp, r, revtime);
-            let cache = CacheValue::new(RLPF::new(ltemp, lpf, 50.0));
-            wet = wet + Value::<T>::from(cache.clone());
-            temp = cache.into();
+        if self.input.is_finished() {
+            self.finished_samples += samples;
+        } else {
+            self.finished_samples = 0;
         }
+    }
 
-        let mut output: Value<_> = Value::<T>::from(dry) * T::from(mix);
-        output = output + wet * (T::one().into() - T::from(mix));
-
-        Reverb { output: output }
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
     }
-}
 
-impl<'a, T: Default> ValueNode for Reverb<'a, T> {
-    type T = T;
-    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        self.output.fill_buffer(env, buffer, samples);
+    fn is_finished(&self) -> bool {
+        self.input.is_finished() && self.finished_samples >= self.buffer.len()
     }
 }
 
@@ -112,6 +77,9 @@ pub struct RingModulator<'a, T> {
     input: Value<'a, T>,
     modulator: Value<'a, T>,
     mix: Value<'a, T>,
+    input_buffer: Vec<T>,
+    modulator_buffer: Vec<T>,
+    mix_buffer: Vec<T>,
 }
 
 impl<'a, T> RingModulator<'a, T> {
@@ -124,6 +92,9 @@ impl<'a, T> RingModulator<'a, T> {
             input: input.into(),
             modulator: modulator.into(),
             mix: mix.into(),
+            input_buffer: vec![],
+            modulator_buffer: vec![],
+            mix_buffer: vec![],
         }
     }
 }
@@ -131,45 +102,72 @@ impl<'a, T> RingModulator<'a, T> {
 impl<'a, T: Copy + Num + Default> ValueNode for RingModulator<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
-        let mut modulator: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.modulator.fill_buffer(env, &mut modulator, samples);
-        let mut mix: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.mix.fill_buffer(env, &mut mix, samples);
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        let modulator = scratch(&mut self.modulator_buffer, samples);
+        self.modulator.fill_buffer(env, modulator, samples);
+        let mix = scratch(&mut self.mix_buffer, samples);
+        self.mix.fill_buffer(env, mix, samples);
 
         buffer[0..samples]
             .iter_mut()
-            .zip(input)
-            .zip(modulator)
-            .zip(mix)
-            .for_each(|(((b, v), modulator), mix)| {
+            .zip(input.iter())
+            .zip(modulator.iter())
+            .zip(mix.iter())
+            .for_each(|(((b, &v), &modulator), &mix)| {
                 *b = (T::one() - mix) * v + mix * modulator * v;
             });
     }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.modulator_buffer, max_block_size);
+        scratch(&mut self.mix_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.modulator.prepare(max_block_size);
+        self.mix.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.input.is_finished()
+    }
 }
 
 pub struct SoftClip<'a, T> {
     input: Value<'a, T>,
+    input_buffer: Vec<T>,
 }
 
 impl<'a, T> SoftClip<'a, T> {
     pub fn new(input: impl Into<Value<'a, T>>) -> Self {
         Self {
             input: input.into(),
+            input_buffer: vec![],
         }
     }
 }
 
-impl<'a, T: Default + Into<f64> + From<f64>> ValueNode for SoftClip<'a, T> {
+impl<'a, T: Default + Copy + Into<f64> + From<f64>> ValueNode for SoftClip<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
-        buffer[0..samples].iter_mut().zip(input).for_each(|(b, v)| {
-            let v: f64 = v.into();
-            *b = (v - v.powf(3.0) / 3.0).into();
-        });
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        buffer[0..samples]
+            .iter_mut()
+            .zip(input.iter())
+            .for_each(|(b, &v)| {
+                let v: f64 = v.into();
+                *b = (v - v.powf(3.0) / 3.0).into();
+            });
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.input.is_finished()
     }
 }
 
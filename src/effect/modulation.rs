@@ -0,0 +1,420 @@
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+use crate::{
+    effect::{DelayInterpolation, DelayLine, DelayTap},
+    filter::FILTER_TAIL,
+    value::{scratch, Frame, MultiSample, Tail, Value, ValueNode},
+    Env,
+};
+
+// How far either side of its centre frequency a phaser at full depth sweeps, in octaves
+const PHASER_OCTAVES: f64 = 2.0;
+
+// Sine LFO running from -1 to 1
+#[derive(Copy, Clone, Debug, Default)]
+struct Lfo {
+    phase: f64,
+}
+
+impl Lfo {
+    fn with_phase(phase: f64) -> Self {
+        Self { phase }
+    }
+
+    fn next(&mut self, rate: f64, sample_rate: u32) -> f64 {
+        let v = (self.phase * 2.0 * PI).sin();
+        self.phase = (self.phase + rate / sample_rate as f64).fract();
+        v
+    }
+}
+
+// Several copies of the input, each on its own slowly wandering delay and panned across the
+// stereo field. Depth, from 0 to 1, is how far each delay swings either side of `delay`.
+pub struct Chorus<'a, T> {
+    input: Value<'a, T>,
+    rate: Value<'a, f64>,
+    depth: Value<'a, f64>,
+    mix: Value<'a, f64>,
+    delay: f64,
+    voices: usize,
+    spread: f64,
+
+    sample_rate: u32,
+    line: DelayLine<MultiSample<f64>>,
+    taps: Vec<(DelayTap<MultiSample<f64>>, Lfo)>,
+    input_buffer: Vec<T>,
+    rate_buffer: Vec<f64>,
+    depth_buffer: Vec<f64>,
+    mix_buffer: Vec<f64>,
+    tail: Tail,
+}
+
+impl<'a, T> Chorus<'a, T> {
+    pub fn new(
+        input: impl Into<Value<'a, T>>,
+        rate: impl Into<Value<'a, f64>>,
+        depth: impl Into<Value<'a, f64>>,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            rate: rate.into(),
+            depth: depth.into(),
+            mix: 0.5.into(),
+            delay: 0.02,
+            voices: 3,
+            spread: 1.0,
+
+            sample_rate: 0,
+            line: DelayLine::new(0),
+            taps: vec![],
+            input_buffer: vec![],
+            rate_buffer: vec![],
+            depth_buffer: vec![],
+            mix_buffer: vec![],
+            tail: Tail::default(),
+        }
+    }
+
+    pub fn voices(mut self, voices: usize) -> Self {
+        self.voices = voices.max(1);
+        self
+    }
+
+    // How much of the stereo field the voices are spread over, from 0 (all in the middle)
+    // to 1 (hard left to hard right)
+    pub fn spread(mut self, spread: f64) -> Self {
+        self.spread = spread.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn delay(mut self, delay: f64) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn mix(mut self, mix: impl Into<Value<'a, f64>>) -> Self {
+        self.mix = mix.into();
+        self
+    }
+}
+
+impl<'a, T: Frame> ValueNode for Chorus<'a, T> {
+    type T = MultiSample<f64>;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.line = DelayLine::new((self.delay * 2.0 * env.sample_rate as f64) as usize + 4);
+            let voices = self.voices;
+            self.taps = (0..voices)
+                .map(|i| {
+                    (
+                        DelayTap::new(DelayInterpolation::Cubic),
+                        Lfo::with_phase(i as f64 / voices as f64),
+                    )
+                })
+                .collect();
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        let rate = scratch(&mut self.rate_buffer, samples);
+        let depth = scratch(&mut self.depth_buffer, samples);
+        let mix = scratch(&mut self.mix_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.rate.fill_buffer(env, rate, samples);
+        self.depth.fill_buffer(env, depth, samples);
+        self.mix.fill_buffer(env, mix, samples);
+
+        let voices = self.taps.len();
+        let normalize = 1.0 / (voices as f64).sqrt();
+        for i in 0..samples {
+            let dry = MultiSample(
+                input[i].channel(0),
+                input[i].channel(T::CHANNELS.min(2) - 1),
+            );
+            let depth = depth[i].clamp(0.0, 0.95);
+            let mut wet = MultiSample(0.0, 0.0);
+            for (v, (tap, lfo)) in self.taps.iter_mut().enumerate() {
+                let swing = 1.0 + depth * lfo.next(rate[i], env.sample_rate);
+                let frame = tap.read(&self.line, self.delay * swing * env.sample_rate as f64);
+                let pan = if voices > 1 {
+                    self.spread * (2.0 * v as f64 / (voices - 1) as f64 - 1.0)
+                } else {
+                    0.0
+                };
+                let angle = (pan + 1.0) * PI / 4.0;
+                wet.0 += frame.0 * angle.cos() * normalize;
+                wet.1 += frame.1 * angle.sin() * normalize;
+            }
+            self.line.push(dry);
+            buffer[i] = MultiSample(
+                dry.0 * (1.0 - mix[i]) + wet.0 * mix[i],
+                dry.1 * (1.0 - mix[i]) + wet.1 * mix[i],
+            );
+        }
+        let required = self.line.capacity();
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], required);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.rate_buffer, max_block_size);
+        scratch(&mut self.depth_buffer, max_block_size);
+        scratch(&mut self.mix_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.rate.prepare(max_block_size);
+        self.depth.prepare(max_block_size);
+        self.mix.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+// A very short swept delay fed back into itself and mixed with the dry signal. Depth, from 0
+// to 1, is how far the delay swings either side of `delay`.
+pub struct Flanger<'a, T> {
+    input: Value<'a, T>,
+    rate: Value<'a, f64>,
+    depth: Value<'a, f64>,
+    feedback: Value<'a, f64>,
+    mix: Value<'a, f64>,
+    delay: f64,
+
+    sample_rate: u32,
+    line: DelayLine<T>,
+    tap: DelayTap<T>,
+    lfo: Lfo,
+    input_buffer: Vec<T>,
+    rate_buffer: Vec<f64>,
+    depth_buffer: Vec<f64>,
+    feedback_buffer: Vec<f64>,
+    mix_buffer: Vec<f64>,
+    tail: Tail,
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>>
+    Flanger<'a, T>
+{
+    pub fn new(
+        input: impl Into<Value<'a, T>>,
+        rate: impl Into<Value<'a, f64>>,
+        depth: impl Into<Value<'a, f64>>,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            rate: rate.into(),
+            depth: depth.into(),
+            feedback: 0.5.into(),
+            mix: 0.5.into(),
+            delay: 0.003,
+
+            sample_rate: 0,
+            line: DelayLine::new(0),
+            tap: DelayTap::new(DelayInterpolation::Cubic),
+            lfo: Lfo::default(),
+            input_buffer: vec![],
+            rate_buffer: vec![],
+            depth_buffer: vec![],
+            feedback_buffer: vec![],
+            mix_buffer: vec![],
+            tail: Tail::default(),
+        }
+    }
+
+    pub fn feedback(mut self, feedback: impl Into<Value<'a, f64>>) -> Self {
+        self.feedback = feedback.into();
+        self
+    }
+
+    pub fn mix(mut self, mix: impl Into<Value<'a, f64>>) -> Self {
+        self.mix = mix.into();
+        self
+    }
+
+    pub fn delay(mut self, delay: f64) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> ValueNode
+    for Flanger<'a, T>
+{
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.line = DelayLine::new((self.delay * 2.0 * env.sample_rate as f64) as usize + 4);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        let rate = scratch(&mut self.rate_buffer, samples);
+        let depth = scratch(&mut self.depth_buffer, samples);
+        let feedback = scratch(&mut self.feedback_buffer, samples);
+        let mix = scratch(&mut self.mix_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.rate.fill_buffer(env, rate, samples);
+        self.depth.fill_buffer(env, depth, samples);
+        self.feedback.fill_buffer(env, feedback, samples);
+        self.mix.fill_buffer(env, mix, samples);
+
+        for i in 0..samples {
+            let depth = depth[i].clamp(0.0, 1.0);
+            let swing = 1.0 + depth * self.lfo.next(rate[i], env.sample_rate);
+            let wet = self
+                .tap
+                .read(&self.line, self.delay * swing * env.sample_rate as f64);
+            let feedback = feedback[i].clamp(-0.99, 0.99);
+            self.line.push(input[i] + wet * T::from(feedback));
+            buffer[i] = input[i] * T::from(1.0 - mix[i]) + wet * T::from(mix[i]);
+        }
+        let required = self.line.capacity();
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], required);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.rate_buffer, max_block_size);
+        scratch(&mut self.depth_buffer, max_block_size);
+        scratch(&mut self.feedback_buffer, max_block_size);
+        scratch(&mut self.mix_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.rate.prepare(max_block_size);
+        self.depth.prepare(max_block_size);
+        self.feedback.prepare(max_block_size);
+        self.mix.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+// A chain of first order all-pass stages whose break frequency is swept around `frequency`,
+// mixed with the dry signal to make moving notches. Depth, from 0 to 1, sweeps up to
+// PHASER_OCTAVES either side.
+pub struct Phaser<'a, T> {
+    input: Value<'a, T>,
+    rate: Value<'a, f64>,
+    depth: Value<'a, f64>,
+    feedback: Value<'a, f64>,
+    mix: Value<'a, f64>,
+    frequency: f64,
+
+    // Previous input and output of each stage
+    stages: Vec<(T, T)>,
+    last: T,
+    lfo: Lfo,
+    input_buffer: Vec<T>,
+    rate_buffer: Vec<f64>,
+    depth_buffer: Vec<f64>,
+    feedback_buffer: Vec<f64>,
+    mix_buffer: Vec<f64>,
+    tail: Tail,
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> Phaser<'a, T> {
+    pub fn new(
+        input: impl Into<Value<'a, T>>,
+        rate: impl Into<Value<'a, f64>>,
+        depth: impl Into<Value<'a, f64>>,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            rate: rate.into(),
+            depth: depth.into(),
+            feedback: 0.0.into(),
+            mix: 0.5.into(),
+            frequency: 800.0,
+
+            stages: vec![(T::default(), T::default()); 4],
+            last: T::default(),
+            lfo: Lfo::default(),
+            input_buffer: vec![],
+            rate_buffer: vec![],
+            depth_buffer: vec![],
+            feedback_buffer: vec![],
+            mix_buffer: vec![],
+            tail: Tail::default(),
+        }
+    }
+
+    pub fn stages(mut self, stages: usize) -> Self {
+        self.stages = vec![(T::default(), T::default()); stages.max(1)];
+        self
+    }
+
+    pub fn frequency(mut self, frequency: f64) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn feedback(mut self, feedback: impl Into<Value<'a, f64>>) -> Self {
+        self.feedback = feedback.into();
+        self
+    }
+
+    pub fn mix(mut self, mix: impl Into<Value<'a, f64>>) -> Self {
+        self.mix = mix.into();
+        self
+    }
+}
+
+impl<'a, T: Frame + Add<Output = T> + Sub<Output = T> + Mul<Output = T> + From<f64>> ValueNode
+    for Phaser<'a, T>
+{
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let input = scratch(&mut self.input_buffer, samples);
+        let rate = scratch(&mut self.rate_buffer, samples);
+        let depth = scratch(&mut self.depth_buffer, samples);
+        let feedback = scratch(&mut self.feedback_buffer, samples);
+        let mix = scratch(&mut self.mix_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.rate.fill_buffer(env, rate, samples);
+        self.depth.fill_buffer(env, depth, samples);
+        self.feedback.fill_buffer(env, feedback, samples);
+        self.mix.fill_buffer(env, mix, samples);
+
+        let nyquist = env.sample_rate as f64 / 2.0;
+        for i in 0..samples {
+            let depth = depth[i].clamp(0.0, 1.0);
+            let octaves = depth * PHASER_OCTAVES * self.lfo.next(rate[i], env.sample_rate);
+            let frequency = (self.frequency * 2.0f64.powf(octaves)).min(nyquist * 0.95);
+            let t = (PI * frequency / env.sample_rate as f64).tan();
+            let a = T::from((t - 1.0) / (t + 1.0));
+
+            let feedback = feedback[i].clamp(-0.95, 0.95);
+            let mut x = input[i] + self.last * T::from(feedback);
+            for (x1, y1) in &mut self.stages {
+                let y = a * x + *x1 - a * *y1;
+                *x1 = x;
+                *y1 = y;
+                x = y;
+            }
+            self.last = x;
+            buffer[i] = input[i] * T::from(1.0 - mix[i]) + x * T::from(mix[i]);
+        }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], FILTER_TAIL);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.rate_buffer, max_block_size);
+        scratch(&mut self.depth_buffer, max_block_size);
+        scratch(&mut self.feedback_buffer, max_block_size);
+        scratch(&mut self.mix_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.rate.prepare(max_block_size);
+        self.depth.prepare(max_block_size);
+        self.feedback.prepare(max_block_size);
+        self.mix.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
@@ -0,0 +1,306 @@
+use std::f64::consts::PI;
+use std::marker::PhantomData;
+
+use crate::{
+    effect::{DelayInterpolation, DelayLine, DelayTap, FeedbackFilter, OnePole},
+    value::{scratch, Frame, Tail, Value, ValueNode},
+    Env,
+};
+
+// Lengths of the feedback delay lines at size 1.0, in seconds. None of them share a common
+// factor so their echoes don't pile up on the same samples.
+const LINE_LENGTHS: [f64; 8] = [
+    0.0297, 0.0371, 0.0411, 0.0437, 0.0533, 0.0599, 0.0671, 0.0733,
+];
+// Early reflections at size 1.0 as (time in seconds, gain, pan from -1 to 1)
+const EARLY_REFLECTIONS: [(f64, f64, f64); 6] = [
+    (0.0071, 0.8, -0.6),
+    (0.0113, 0.7, 0.7),
+    (0.0179, 0.6, -0.3),
+    (0.0233, 0.5, 0.4),
+    (0.0317, 0.4, -0.8),
+    (0.0419, 0.3, 0.9),
+];
+// How far, in seconds, the line lengths wander to break up metallic ringing
+const MODULATION_DEPTH: f64 = 0.0002;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReverbMatrix {
+    Householder,
+    Hadamard,
+}
+
+// Mixes every line into every other while keeping the total energy the same
+fn mix_lines(matrix: ReverbMatrix, lines: &mut [f64; 8]) {
+    match matrix {
+        ReverbMatrix::Householder => {
+            let sum: f64 = lines.iter().sum::<f64>() * 2.0 / lines.len() as f64;
+            for v in lines.iter_mut() {
+                *v -= sum;
+            }
+        }
+        ReverbMatrix::Hadamard => {
+            let mut h = 1;
+            while h < lines.len() {
+                for i in (0..lines.len()).step_by(h * 2) {
+                    for j in i..i + h {
+                        let (a, b) = (lines[j], lines[j + h]);
+                        lines[j] = a + b;
+                        lines[j + h] = a - b;
+                    }
+                }
+                h *= 2;
+            }
+            let scale = 1.0 / (lines.len() as f64).sqrt();
+            for v in lines.iter_mut() {
+                *v *= scale;
+            }
+        }
+    }
+}
+
+struct Line {
+    delay: DelayLine<f64>,
+    tap: DelayTap<f64>,
+    length: f64,
+    gain: f64,
+    damping: OnePole<f64>,
+    phase: f64,
+    rate: f64,
+}
+
+// Feedback delay network reverb. Any input is summed to mono and the output is the dry
+// signal with a decorrelated stereo tail mixed in. Decay is the time in seconds for the tail
+// to fall by 60dB, and damping the frequency above which it dies away faster.
+pub struct Reverb<'a, T, O> {
+    input: Value<'a, T>,
+    size: f64,
+    damping: f64,
+    decay: f64,
+    predelay: f64,
+    width: f64,
+    mix: f64,
+    matrix: ReverbMatrix,
+
+    sample_rate: u32,
+    predelay_line: DelayLine<f64>,
+    lines: Vec<Line>,
+    input_buffer: Vec<T>,
+    tail: Tail,
+    _output: PhantomData<O>,
+}
+
+impl<'a, T, O> Reverb<'a, T, O> {
+    pub fn new(input: impl Into<Value<'a, T>>) -> Self {
+        Self {
+            input: input.into(),
+            size: 1.0,
+            damping: 4000.0,
+            decay: 2.0,
+            predelay: 0.02,
+            width: 1.0,
+            mix: 0.3,
+            matrix: ReverbMatrix::Householder,
+
+            sample_rate: 0,
+            predelay_line: DelayLine::new(0),
+            lines: vec![],
+            input_buffer: vec![],
+            tail: Tail::default(),
+            _output: PhantomData,
+        }
+    }
+
+    // Scales the spacing of the reflections and the delay lines, from roughly 0.25 for a
+    // small room to 2.0 for a hall
+    pub fn size(mut self, size: f64) -> Self {
+        self.size = size.max(0.05);
+        self
+    }
+
+    pub fn damping(mut self, damping: f64) -> Self {
+        self.damping = damping;
+        self
+    }
+
+    pub fn decay(mut self, decay: f64) -> Self {
+        self.decay = decay.max(0.01);
+        self
+    }
+
+    pub fn predelay(mut self, predelay: f64) -> Self {
+        self.predelay = predelay.max(0.0);
+        self
+    }
+
+    // Stereo width of the wet signal, 0 for mono up to 1 for fully decorrelated channels
+    pub fn width(mut self, width: f64) -> Self {
+        self.width = width.clamp(0.0, 1.0);
+        self
+    }
+
+    // How much of the wet signal is in the output, from 0 to 1
+    pub fn mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn matrix(mut self, matrix: ReverbMatrix) -> Self {
+        self.matrix = matrix;
+        self
+    }
+
+    fn build(&mut self, sample_rate: u32) {
+        let sr = sample_rate as f64;
+        let longest_reflection = EARLY_REFLECTIONS
+            .iter()
+            .fold(0.0f64, |m, (time, _, _)| m.max(*time));
+        let predelay_len = (self.predelay + longest_reflection * self.size) * sr;
+        self.predelay_line = DelayLine::new(predelay_len as usize + 4);
+        self.lines = LINE_LENGTHS
+            .iter()
+            .enumerate()
+            .map(|(i, length)| {
+                let length = length * self.size;
+                let max = length + MODULATION_DEPTH;
+                Line {
+                    delay: DelayLine::new((max * sr) as usize + 4),
+                    tap: DelayTap::new(DelayInterpolation::Cubic),
+                    length,
+                    gain: 10.0f64.powf(-3.0 * length / self.decay),
+                    damping: OnePole::low_pass(self.damping),
+                    phase: i as f64 / LINE_LENGTHS.len() as f64,
+                    rate: 0.13 + 0.07 * i as f64,
+                }
+            })
+            .collect();
+    }
+}
+
+impl<'a, T: Frame, O: Frame> ValueNode for Reverb<'a, T, O> {
+    type T = O;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            self.build(env.sample_rate);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+
+        let sr = env.sample_rate as f64;
+        let predelay = self.predelay * sr;
+        let normalize = 1.0 / (self.lines.len() as f64).sqrt();
+        // Reflections are summed as uncorrelated signals, like the lines are
+        let early_normalize = 1.0 / (EARLY_REFLECTIONS.len() as f64).sqrt();
+        for i in 0..samples {
+            let frame = input[i];
+            let dry_left = frame.channel(0);
+            let dry_right = frame.channel(T::CHANNELS.min(2) - 1);
+            let mono = (0..T::CHANNELS).map(|c| frame.channel(c)).sum::<f64>() / T::CHANNELS as f64;
+            self.predelay_line.push(mono);
+            let delayed = self.predelay_line.get(predelay as usize + 1);
+
+            let (mut left, mut right) = (0.0, 0.0);
+            for (time, gain, pan) in EARLY_REFLECTIONS.iter() {
+                let reflection = self
+                    .predelay_line
+                    .get((predelay + time * self.size * sr) as usize + 1)
+                    * gain
+                    * early_normalize;
+                let angle = (pan + 1.0) * PI / 4.0;
+                left += reflection * angle.cos();
+                right += reflection * angle.sin();
+            }
+
+            let mut outputs = [0.0; 8];
+            for (n, line) in self.lines.iter_mut().enumerate() {
+                let wobble = (line.phase * 2.0 * PI).sin() * MODULATION_DEPTH;
+                line.phase = (line.phase + line.rate / sr).fract();
+                let out = line.tap.read(&line.delay, (line.length + wobble) * sr);
+                outputs[n] = out;
+                // Alternate signs so the two channels end up built from different lines
+                if n % 2 == 0 {
+                    left += out * normalize;
+                    right += out * normalize * if n % 4 == 0 { 1.0 } else { -1.0 };
+                } else {
+                    right += out * normalize;
+                    left -= out * normalize * if n % 4 == 1 { 1.0 } else { -1.0 };
+                }
+            }
+
+            let mut feedback = outputs;
+            mix_lines(self.matrix, &mut feedback);
+            for (n, line) in self.lines.iter_mut().enumerate() {
+                let damped = line
+                    .damping
+                    .process(env.sample_rate, feedback[n] * line.gain);
+                let sign = if n % 2 == 0 { 1.0 } else { -1.0 };
+                line.delay.push(damped + delayed * sign * normalize);
+            }
+
+            let mid = (left + right) / 2.0;
+            let side = (left - right) / 2.0 * self.width;
+            let (wet_left, wet_right) = (mid + side, mid - side);
+            buffer[i] = O::from_channels(&[
+                dry_left * (1.0 - self.mix) + wet_left * self.mix,
+                dry_right * (1.0 - self.mix) + wet_right * self.mix,
+            ]);
+        }
+        let required = (self.decay * sr) as usize;
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], required);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::MultiSample;
+
+    // A single full scale sample, finished as soon as it has played
+    struct Impulse(bool);
+
+    impl ValueNode for Impulse {
+        type T = f64;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = if self.0 { 0.0 } else { 1.0 };
+                self.0 = true;
+            }
+        }
+
+        fn is_finished(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn impulse_decays_until_finished() {
+        let mut reverb: Value<MultiSample<f64>> =
+            Reverb::new(Impulse(false)).decay(0.3).mix(1.0).into();
+        let env = Env::new(8000);
+        let mut buffer = vec![MultiSample(0.0, 0.0); 400];
+        let mut peak: f64 = 0.0;
+        let mut blocks = 0;
+        while !reverb.is_finished() {
+            reverb.fill_buffer(&env, &mut buffer, 400);
+            for frame in &buffer {
+                peak = peak.max(frame.0.abs()).max(frame.1.abs());
+            }
+            blocks += 1;
+            assert!(blocks < 200, "still ringing after 10 seconds");
+        }
+        // It rang for a while and never came near clipping
+        assert!(blocks > 5);
+        assert!(peak > 0.01 && peak < 1.0, "{}", peak);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{
+    envelope::Curve,
+    value::{scratch, Value, ValueNode},
+    Env,
+};
+
+#[derive(Copy, Clone, Debug)]
+struct Segment {
+    time: f64,
+    level: f64,
+    curve: Curve,
+}
+
+// An envelope made of any number of segments, each moving to a new level over some time.
+// Point 0 is the start level and segment n ends at point n + 1.
+pub struct Breakpoints<'a> {
+    start_level: f64,
+    segments: Vec<Segment>,
+    sustain_point: Option<usize>,
+    loop_points: Option<(usize, usize)>,
+    gate: Option<Value<'a, bool>>,
+}
+
+impl<'a> Breakpoints<'a> {
+    pub fn new(start_level: f64) -> Self {
+        Self {
+            start_level,
+            segments: vec![],
+            sustain_point: None,
+            loop_points: None,
+            gate: None,
+        }
+    }
+
+    pub fn segment(mut self, time: f64, level: f64, curve: Curve) -> Self {
+        self.segments.push(Segment { time, level, curve });
+        self
+    }
+
+    // Hold at this point while the gate is high. Has no effect without a gate.
+    pub fn sustain_point(mut self, point: usize) -> Self {
+        self.sustain_point = Some(point);
+        self
+    }
+
+    // Jump back to `start` whenever `end` is reached, for as long as the gate is high or
+    // forever if there is no gate.
+    pub fn loop_points(mut self, start: usize, end: usize) -> Self {
+        self.loop_points = Some((start, end));
+        self
+    }
+
+    // Start the envelope each time the gate rises. When it falls the envelope skips ahead
+    // to the segment after the sustain point (or the loop end) and plays out from there.
+    pub fn gate(mut self, gate: impl Into<Value<'a, bool>>) -> Self {
+        self.gate = Some(gate.into());
+        self
+    }
+}
+
+impl<'a> From<Breakpoints<'a>> for Value<'a, f64> {
+    fn from(breakpoints: Breakpoints<'a>) -> Value<'a, f64> {
+        let points = breakpoints.segments.len();
+        let sustain_point = breakpoints.sustain_point.filter(|p| *p > 0 && *p <= points);
+        let loop_points = breakpoints
+            .loop_points
+            .filter(|(start, end)| start < end && *end <= points);
+        let state = if breakpoints.gate.is_some() {
+            State::Waiting
+        } else if points == 0 {
+            State::Done
+        } else {
+            State::Running
+        };
+        RunningBreakpoints {
+            segments: breakpoints.segments,
+            sustain_point,
+            loop_points,
+            gate: breakpoints.gate,
+
+            gate_buffer: vec![],
+            gate_high: false,
+            state,
+            current: 0,
+            from: breakpoints.start_level,
+            level: breakpoints.start_level,
+            progress: 0.0,
+        }
+        .into()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Waiting,
+    Running,
+    Holding,
+    Done,
+}
+
+struct RunningBreakpoints<'a> {
+    segments: Vec<Segment>,
+    sustain_point: Option<usize>,
+    loop_points: Option<(usize, usize)>,
+    gate: Option<Value<'a, bool>>,
+
+    gate_buffer: Vec<bool>,
+    gate_high: bool,
+    state: State,
+    current: usize,
+    from: f64,
+    level: f64,
+    progress: f64,
+}
+
+impl<'a> RunningBreakpoints<'a> {
+    fn start_segment(&mut self, segment: usize) {
+        if segment >= self.segments.len() {
+            self.state = State::Done;
+        } else {
+            self.state = State::Running;
+            self.current = segment;
+            self.from = self.level;
+            self.progress = 0.0;
+        }
+    }
+
+    fn release_point(&self) -> Option<usize> {
+        self.sustain_point
+            .or_else(|| self.loop_points.map(|(_, end)| end))
+    }
+
+    fn gate_changed(&mut self, high: bool) {
+        self.gate_high = high;
+        if high {
+            self.start_segment(0);
+        } else if let Some(point) = self.release_point() {
+            let before_release = self.state == State::Running && self.current < point;
+            if self.state == State::Holding || before_release {
+                self.start_segment(point);
+            }
+        }
+    }
+
+    fn next_level(&mut self, dt: f64) -> f64 {
+        if self.state != State::Running {
+            return self.level;
+        }
+        let segment = self.segments[self.current];
+        let value = self.from + (segment.level - self.from) * segment.curve.shape(self.progress);
+        if segment.time > 0.0 {
+            self.progress += dt / segment.time;
+        } else {
+            self.progress = 1.0;
+        }
+        self.level = value;
+        if self.progress >= 1.0 {
+            self.level = segment.level;
+            let point = self.current + 1;
+            let held = self.gate.is_some() && self.gate_high;
+            let looping = self.gate.is_none() || self.gate_high;
+            match self.loop_points {
+                _ if held && self.sustain_point == Some(point) => self.state = State::Holding,
+                Some((start, end)) if looping && end == point => self.start_segment(start),
+                _ => self.start_segment(point),
+            }
+        }
+        value
+    }
+}
+
+impl<'a> ValueNode for RunningBreakpoints<'a> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let dt = 1.0 / env.sample_rate as f64;
+        if let Some(gate) = &mut self.gate {
+            let gate_buffer = scratch(&mut self.gate_buffer, samples);
+            gate.fill_buffer(env, gate_buffer, samples);
+        }
+        for (i, level) in buffer[0..samples].iter_mut().enumerate() {
+            if self.gate.is_some() && self.gate_buffer[i] != self.gate_high {
+                self.gate_changed(self.gate_buffer[i]);
+            }
+            *level = self.next_level(dt);
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        if let Some(gate) = &mut self.gate {
+            scratch(&mut self.gate_buffer, max_block_size);
+            gate.prepare(max_block_size);
+        }
+    }
+
+    // An envelope that ends away from zero keeps holding its last level, so it only counts
+    // as finished once it has come to rest at zero.
+    fn is_finished(&self) -> bool {
+        let stopped = self.state == State::Done || self.state == State::Waiting;
+        let gate_finished = match &self.gate {
+            Some(gate) => !self.gate_high && gate.is_finished(),
+            None => true,
+        };
+        stopped && gate_finished && self.level == 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // High for the first `.0` samples and low after that
+    struct Gate(usize, usize);
+
+    impl ValueNode for Gate {
+        type T = bool;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = self.1 < self.0;
+                self.1 += 1;
+            }
+        }
+
+        fn is_finished(&self) -> bool {
+            self.1 >= self.0
+        }
+    }
+
+    // Segments are 8 samples long at this rate
+    const STEP: f64 = 8.0 / 1024.0;
+
+    fn render(breakpoints: Breakpoints, len: usize) -> (Vec<f64>, bool) {
+        let mut value: Value<f64> = breakpoints.into();
+        let env = Env::new(1024);
+        let mut output = vec![0.0; len];
+        for block in output.chunks_mut(7) {
+            let samples = block.len();
+            value.fill_buffer(&env, block, samples);
+        }
+        (output, value.is_finished())
+    }
+
+    #[test]
+    fn sustain_point_holds_until_the_gate_falls() {
+        let breakpoints = Breakpoints::new(0.0)
+            .segment(STEP, 1.0, Curve::Linear)
+            .segment(STEP, 0.5, Curve::Linear)
+            .segment(STEP, 0.0, Curve::Linear)
+            .sustain_point(2)
+            .gate(Gate(40, 0));
+        let (output, finished) = render(breakpoints, 64);
+        assert_eq!(output[4], 0.5);
+        assert_eq!(output[8], 1.0);
+        assert!(output[16..41].iter().all(|v| *v == 0.5));
+        assert_eq!(output[44], 0.25);
+        assert!(output[48..].iter().all(|v| *v == 0.0));
+        assert!(finished);
+    }
+
+    #[test]
+    fn loops_forever_without_a_gate() {
+        let breakpoints = Breakpoints::new(0.0)
+            .segment(STEP, 1.0, Curve::Linear)
+            .segment(STEP, 0.0, Curve::Linear)
+            .segment(STEP, 1.0, Curve::Linear)
+            .loop_points(1, 3);
+        let (output, finished) = render(breakpoints, 200);
+        assert_eq!(output[12], 0.5);
+        for n in 8..184 {
+            assert_eq!(output[n], output[n + 16]);
+        }
+        assert!(!finished);
+    }
+
+    #[test]
+    fn gate_falling_mid_loop_releases_from_the_current_level() {
+        let breakpoints = Breakpoints::new(0.0)
+            .segment(STEP, 1.0, Curve::Linear)
+            .segment(STEP, 0.5, Curve::Linear)
+            .segment(STEP, 1.0, Curve::Linear)
+            .segment(STEP, 0.0, Curve::Linear)
+            .loop_points(1, 3)
+            .gate(Gate(50, 0));
+        let (output, finished) = render(breakpoints, 80);
+        for n in 8..34 {
+            assert_eq!(output[n], output[n + 16]);
+        }
+        let released = output[49];
+        assert!(released > 0.5 && released < 1.0);
+        assert_eq!(output[50], released);
+        assert_eq!(output[54], released / 2.0);
+        assert!(output[58..].iter().all(|v| *v == 0.0));
+        assert!(finished);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{
+    value::{scratch, Frame, Tail, Value, ValueNode},
+    Env,
+};
+
+// Largest absolute value across all channels, so stereo input is followed as one signal
+pub(crate) fn peak<T: Frame>(frame: &T) -> f64 {
+    (0..T::CHANNELS).fold(0.0, |peak, c| peak.max(frame.channel(c).abs()))
+}
+
+// One pole smoothing coefficient for a time constant given in seconds
+pub(crate) fn coefficient(time: f64, sample_rate: u32) -> f64 {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * sample_rate as f64)).exp()
+    }
+}
+
+// Tracks the peak level of its input, rising with the attack time and falling with the
+// release time.
+pub struct PeakFollower<'a, T> {
+    input: Value<'a, T>,
+    attack: Value<'a, f64>,
+    release: Value<'a, f64>,
+
+    level: f64,
+    tail: Tail,
+    input_buffer: Vec<T>,
+    attack_buffer: Vec<f64>,
+    release_buffer: Vec<f64>,
+}
+
+impl<'a, T> PeakFollower<'a, T> {
+    pub fn new(
+        input: impl Into<Value<'a, T>>,
+        attack: impl Into<Value<'a, f64>>,
+        release: impl Into<Value<'a, f64>>,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            attack: attack.into(),
+            release: release.into(),
+
+            level: 0.0,
+            tail: Tail::default(),
+            input_buffer: vec![],
+            attack_buffer: vec![],
+            release_buffer: vec![],
+        }
+    }
+}
+
+impl<'a, T: Frame> ValueNode for PeakFollower<'a, T> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let input = scratch(&mut self.input_buffer, samples);
+        let attack = scratch(&mut self.attack_buffer, samples);
+        let release = scratch(&mut self.release_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.attack.fill_buffer(env, attack, samples);
+        self.release.fill_buffer(env, release, samples);
+        for i in 0..samples {
+            let x = peak(&input[i]);
+            let time = if x > self.level {
+                attack[i]
+            } else {
+                release[i]
+            };
+            let c = coefficient(time, env.sample_rate);
+            self.level = x + (self.level - x) * c;
+            buffer[i] = self.level;
+        }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], 1);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.attack_buffer, max_block_size);
+        scratch(&mut self.release_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+        self.attack.prepare(max_block_size);
+        self.release.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+// Root mean square level of its input over a sliding window, in seconds. Channels are
+// averaged together.
+pub struct Rms<'a, T> {
+    input: Value<'a, T>,
+    window: f64,
+
+    squares: Vec<f64>,
+    position: usize,
+    sum: f64,
+    sample_rate: u32,
+    tail: Tail,
+    input_buffer: Vec<T>,
+}
+
+impl<'a, T> Rms<'a, T> {
+    pub fn new(input: impl Into<Value<'a, T>>, window: f64) -> Self {
+        Self {
+            input: input.into(),
+            window,
+
+            squares: vec![],
+            position: 0,
+            sum: 0.0,
+            sample_rate: 0,
+            tail: Tail::default(),
+            input_buffer: vec![],
+        }
+    }
+}
+
+impl<'a, T: Frame> ValueNode for Rms<'a, T> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            let len = ((self.window * env.sample_rate as f64) as usize).max(1);
+            self.squares = vec![0.0; len];
+            self.position = 0;
+            self.sum = 0.0;
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        let len = self.squares.len();
+        for i in 0..samples {
+            let frame = &input[i];
+            let square = (0..T::CHANNELS)
+                .map(|c| frame.channel(c) * frame.channel(c))
+                .sum::<f64>()
+                / T::CHANNELS as f64;
+            self.sum += square - self.squares[self.position];
+            self.squares[self.position] = square;
+            self.position += 1;
+            if self.position == len {
+                self.position = 0;
+                // Resum once per window so rounding errors in the running sum can't build up
+                self.sum = self.squares.iter().sum();
+            }
+            buffer[i] = (self.sum.max(0.0) / len as f64).sqrt();
+        }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], len);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
@@ -1,15 +1,78 @@
+mod breakpoints;
+mod follower;
+pub use breakpoints::*;
+pub use follower::*;
+
 use crate::{
-    value::{Value, ValueNode},
+    value::{scratch, Value, ValueNode},
     Env,
 };
 
+// What a gated envelope does when the gate rises while it is still sounding
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    // Start a new attack from the current level
+    Retrigger,
+    // Start a new attack from zero
+    Reset,
+    // Skip the attack and glide back to the sustain level
+    Legato,
+}
+
+// How a segment moves from its start level to its end level
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    // The original ADSR shape: 1 - (1 - t)^n
+    Power(f64),
+    // Fast at first then settling into the end level, like a capacitor charging. The value
+    // sets how pronounced the bend is.
+    Exponential(f64),
+    // The mirror of Exponential: slow at first then rushing into the end level
+    Logarithmic(f64),
+    // Eases in and out of both ends
+    SCurve,
+    // An RC curve aimed past the end level by this fraction of the segment and cut off when
+    // it gets there, so it arrives still moving like an analog envelope does
+    Analog(f64),
+}
+
+impl Curve {
+    // Maps progress through a segment, from 0 to 1, to the fraction of the way from the
+    // start level to the end level.
+    pub fn shape(self, progress: f64) -> f64 {
+        let t = progress.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => t,
+            Curve::Power(n) => 1.0 - (1.0 - t).powf(n),
+            Curve::Exponential(k) if k.abs() > 1e-9 => (1.0 - (-k * t).exp()) / (1.0 - (-k).exp()),
+            Curve::Logarithmic(k) if k.abs() > 1e-9 => ((k * t).exp() - 1.0) / (k.exp() - 1.0),
+            Curve::Exponential(_) | Curve::Logarithmic(_) => t,
+            Curve::SCurve => t * t * (3.0 - 2.0 * t),
+            Curve::Analog(overshoot) => {
+                let o = overshoot.max(1e-6);
+                (1.0 + o) * (1.0 - (o / (1.0 + o)).powf(t))
+            }
+        }
+    }
+}
+
 pub struct ADSR {
     attack: Option<f64>,
     sustain_level: Option<f64>,
     decay: Option<f64>,
     duration: Option<f64>,
     release: Option<f64>,
-    curve: Option<f64>,
+    attack_curve: Curve,
+    decay_curve: Curve,
+    release_curve: Curve,
+    trigger: TriggerMode,
+}
+
+impl Default for ADSR {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ADSR {
@@ -20,7 +83,10 @@ impl ADSR {
             decay: None,
             duration: None,
             release: None,
-            curve: None,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+            trigger: TriggerMode::Retrigger,
         }
     }
 
@@ -49,10 +115,56 @@ impl ADSR {
         self
     }
 
+    // Sets every segment to Curve::Power(curve)
     pub fn curve(mut self, curve: f64) -> Self {
-        self.curve = Some(curve);
+        self.attack_curve = Curve::Power(curve);
+        self.decay_curve = Curve::Power(curve);
+        self.release_curve = Curve::Power(curve);
         self
     }
+
+    pub fn attack_curve(mut self, curve: Curve) -> Self {
+        self.attack_curve = curve;
+        self
+    }
+
+    pub fn decay_curve(mut self, curve: Curve) -> Self {
+        self.decay_curve = curve;
+        self
+    }
+
+    pub fn release_curve(mut self, curve: Curve) -> Self {
+        self.release_curve = curve;
+        self
+    }
+
+    pub fn trigger(mut self, trigger: TriggerMode) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    // Drive the envelope from a gate instead of a fixed duration: attack starts when the
+    // gate rises, sustain holds while it stays high and release starts when it falls.
+    pub fn gate<'a>(self, gate: impl Into<Value<'a, bool>>) -> GatedADSR<'a> {
+        GatedADSR {
+            attack: self.attack.unwrap_or(0.1),
+            sustain_level: self.sustain_level.unwrap_or(1.0),
+            decay: self.decay.unwrap_or(0.0),
+            release: self.release.unwrap_or(0.1),
+            attack_curve: self.attack_curve,
+            decay_curve: self.decay_curve,
+            release_curve: self.release_curve,
+            trigger: self.trigger,
+
+            gate: gate.into(),
+            gate_buffer: vec![],
+            stage: Stage::Idle,
+            gate_high: false,
+            level: 0.0,
+            from: 0.0,
+            progress: 0.0,
+        }
+    }
 }
 
 impl<'a> From<ADSR> for Value<'a, f64> {
@@ -63,7 +175,9 @@ impl<'a> From<ADSR> for Value<'a, f64> {
             decay: adsr.decay.unwrap_or(0.0),
             duration: adsr.duration.unwrap_or(1.0),
             release: adsr.release.unwrap_or(0.1),
-            curve: adsr.curve.unwrap_or(1.0),
+            attack_curve: adsr.attack_curve,
+            decay_curve: adsr.decay_curve,
+            release_curve: adsr.release_curve,
 
             active: true,
             clock: 0.0,
@@ -78,7 +192,9 @@ struct RunningADSR {
     decay: f64,
     duration: f64,
     release: f64,
-    curve: f64,
+    attack_curve: Curve,
+    decay_curve: Curve,
+    release_curve: Curve,
 
     active: bool,
     clock: f64,
@@ -87,22 +203,24 @@ struct RunningADSR {
 impl ValueNode for RunningADSR {
     type T = f64;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
-            buffer[i] = if self.active {
+        for sample in &mut buffer[0..samples] {
+            *sample = if self.active {
+                let decay_start = self.attack;
+                let release_start = decay_start + self.decay + self.duration;
                 let v = if self.clock < self.attack {
-                    let d = self.attack - self.clock;
-                    1.0 - (d / self.attack).powf(self.curve)
-                } else if self.clock < self.attack + self.decay {
-                    let d = (self.attack + self.decay) - self.clock;
-                    1.0 - (d / self.decay).powf(self.clock) * (1.0 - self.sustain_level)
-                } else if self.clock < self.attack + self.decay + self.duration {
+                    self.attack_curve
+                        .shape(segment_progress(self.clock, self.attack))
+                } else if self.clock < decay_start + self.decay {
+                    let p = segment_progress(self.clock - decay_start, self.decay);
+                    1.0 - (1.0 - self.sustain_level) * self.decay_curve.shape(p)
+                } else if self.clock < release_start {
                     self.sustain_level
                 } else {
-                    let d = (self.attack + self.decay + self.duration + self.release) - self.clock;
-                    (d / self.release).powf(self.curve) * self.sustain_level
+                    let p = segment_progress(self.clock - release_start, self.release);
+                    self.sustain_level * (1.0 - self.release_curve.shape(p))
                 };
                 self.clock += 1.0 / env.sample_rate as f64;
-                if self.clock > self.attack + self.decay + self.duration + self.release {
+                if self.clock > release_start + self.release {
                     self.active = false;
                 }
                 v
@@ -111,4 +229,239 @@ impl ValueNode for RunningADSR {
             };
         }
     }
+
+    fn is_finished(&self) -> bool {
+        !self.active
+    }
+}
+
+// How far through a segment `elapsed` seconds is. A zero length segment is already over, as
+// it is for GatedADSR.
+fn segment_progress(elapsed: f64, length: f64) -> f64 {
+    if length > 0.0 {
+        elapsed / length
+    } else {
+        1.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+pub struct GatedADSR<'a> {
+    attack: f64,
+    sustain_level: f64,
+    decay: f64,
+    release: f64,
+    attack_curve: Curve,
+    decay_curve: Curve,
+    release_curve: Curve,
+    trigger: TriggerMode,
+
+    gate: Value<'a, bool>,
+    gate_buffer: Vec<bool>,
+    stage: Stage,
+    gate_high: bool,
+    level: f64,
+    // Level the current stage started from and how far through it we are, from 0 to 1
+    from: f64,
+    progress: f64,
+}
+
+impl<'a> GatedADSR<'a> {
+    fn enter(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.from = self.level;
+        self.progress = 0.0;
+    }
+
+    fn gate_changed(&mut self, high: bool) {
+        if high {
+            match (self.trigger, self.stage) {
+                (TriggerMode::Legato, Stage::Idle) | (TriggerMode::Retrigger, _) => {
+                    self.enter(Stage::Attack)
+                }
+                (TriggerMode::Reset, _) => {
+                    self.level = 0.0;
+                    self.enter(Stage::Attack);
+                }
+                (TriggerMode::Legato, _) => self.enter(Stage::Decay),
+            }
+        } else if self.stage != Stage::Idle {
+            self.enter(Stage::Release);
+        }
+        self.gate_high = high;
+    }
+
+    fn next_level(&mut self, dt: f64) -> f64 {
+        let (length, to, curve, next) = match self.stage {
+            Stage::Idle => return 0.0,
+            Stage::Sustain => return self.sustain_level,
+            Stage::Attack => (self.attack, 1.0, self.attack_curve, Stage::Decay),
+            Stage::Decay => (
+                self.decay,
+                self.sustain_level,
+                self.decay_curve,
+                Stage::Sustain,
+            ),
+            Stage::Release => (self.release, 0.0, self.release_curve, Stage::Idle),
+        };
+        self.level = self.from + (to - self.from) * curve.shape(self.progress);
+        if length > 0.0 {
+            self.progress += dt / length;
+        } else {
+            self.progress = 1.0;
+            self.level = to;
+        }
+        if self.progress >= 1.0 {
+            self.level = to;
+            self.enter(next);
+        }
+        self.level
+    }
+}
+
+impl<'a> ValueNode for GatedADSR<'a> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let gate = scratch(&mut self.gate_buffer, samples);
+        self.gate.fill_buffer(env, gate, samples);
+        let dt = 1.0 / env.sample_rate as f64;
+        for (i, level) in buffer[0..samples].iter_mut().enumerate() {
+            let high = self.gate_buffer[i];
+            if high != self.gate_high {
+                self.gate_changed(high);
+            }
+            *level = self.next_level(dt);
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.gate_buffer, max_block_size);
+        self.gate.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.stage == Stage::Idle && self.gate.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Gate(Vec<bool>, usize);
+
+    impl ValueNode for Gate {
+        type T = bool;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = self.0.get(self.1).cloned().unwrap_or(false);
+                self.1 += 1;
+            }
+        }
+    }
+
+    // Segments of 8 samples each so every step is an exact fraction
+    fn gated(trigger: TriggerMode, gate: Vec<bool>) -> Vec<f64> {
+        let len = gate.len();
+        let mut adsr: Value<f64> = ADSR::new()
+            .attack(8.0 / 1024.0)
+            .decay(8.0 / 1024.0)
+            .sustain(0.5)
+            .release(8.0 / 1024.0)
+            .trigger(trigger)
+            .gate(Gate(gate, 0))
+            .into();
+        let env = Env::new(1024);
+        let mut output = vec![0.0; len];
+        for block in output.chunks_mut(5) {
+            let samples = block.len();
+            adsr.fill_buffer(&env, block, samples);
+        }
+        output
+    }
+
+    // High for 24 samples, low for 4 and high again for 24
+    fn retriggered(trigger: TriggerMode) -> Vec<f64> {
+        let gate = (0..52).map(|n| !(24..28).contains(&n)).collect();
+        gated(trigger, gate)
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-12, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn zero_length_segments_stay_finite() {
+        let env = Env::new(1000);
+        for &(attack, decay, duration, release) in &[
+            (0.0, 0.0, 0.0, 0.0),
+            (0.0, 0.0, 0.005, 0.0),
+            (0.005, 0.0, 0.0, 0.0),
+            (0.0, 0.005, 0.0, 0.005),
+        ] {
+            let mut adsr: Value<f64> = ADSR::new()
+                .attack(attack)
+                .decay(decay)
+                .sustain(0.5)
+                .duration(duration)
+                .release(release)
+                .into();
+            let mut output = vec![0.0; 32];
+            adsr.fill_buffer(&env, &mut output, 32);
+            assert!(output.iter().all(|v| v.is_finite()), "{:?}", output);
+            assert!(adsr.is_finished());
+        }
+    }
+
+    #[test]
+    fn gated_stages() {
+        let output = retriggered(TriggerMode::Retrigger);
+        for (n, v) in output.iter().enumerate().take(7) {
+            assert_close(*v, n as f64 / 8.0);
+        }
+        assert_close(output[7], 1.0);
+        assert_close(output[8], 1.0);
+        assert_close(output[12], 0.75);
+        assert_close(output[15], 0.5);
+        assert!(output[16..25].iter().all(|v| *v == 0.5));
+        assert_close(output[26], 0.375);
+        assert_close(output[27], 0.3125);
+    }
+
+    #[test]
+    fn retrigger_restarts_the_attack_from_the_current_level() {
+        let output = retriggered(TriggerMode::Retrigger);
+        assert_close(output[28], 0.3125);
+        assert!(output[28..35].windows(2).all(|w| w[1] > w[0]));
+        assert_close(output[35], 1.0);
+        assert_close(output[43], 0.5);
+    }
+
+    #[test]
+    fn reset_restarts_the_attack_from_zero() {
+        let output = retriggered(TriggerMode::Reset);
+        assert_close(output[28], 0.0);
+        assert_close(output[29], 0.125);
+        assert_close(output[35], 1.0);
+        assert_close(output[43], 0.5);
+    }
+
+    #[test]
+    fn legato_glides_back_to_the_sustain_level() {
+        let output = retriggered(TriggerMode::Legato);
+        assert_close(output[0], 0.0);
+        assert_close(output[7], 1.0);
+        assert_close(output[28], 0.3125);
+        assert!(output[28..].iter().all(|v| *v <= 0.5));
+        assert_close(output[35], 0.5);
+        assert_close(output[51], 0.5);
+    }
 }
@@ -4,8 +4,11 @@ use std::ops::{Add, Mul, Neg, Sub};
 
 use num::Zero;
 
+// How long a filter must stay silent after its input finishes before it reports being finished
+pub(crate) const FILTER_TAIL: usize = 64;
+
 use crate::{
-    value::{Value, ValueNode},
+    value::{scratch, Frame, Tail, Value, ValueNode},
     Env,
 };
 
@@ -23,6 +26,12 @@ pub struct RLPF<'a, T> {
     y0: T,
     y1: T,
     y2: T,
+
+    input_buffer: Vec<T>,
+    cutoff_buffer: Vec<f64>,
+    q_buffer: Vec<f64>,
+    parameters: Vec<(f64, f64, f64)>,
+    tail: Tail,
 }
 
 impl<'a, T: Copy + Default> RLPF<'a, T> {
@@ -35,32 +44,38 @@ impl<'a, T: Copy + Default> RLPF<'a, T> {
             input: input.into(),
             cutoff: cutoff.into(),
             q: q.into(),
-            cached_cutoff: std::f64::NAN,
-            cached_q: std::f64::NAN,
-            a0: std::f64::NAN,
-            b1: std::f64::NAN,
-            b2: std::f64::NAN,
+            cached_cutoff: f64::NAN,
+            cached_q: f64::NAN,
+            a0: f64::NAN,
+            b1: f64::NAN,
+            b2: f64::NAN,
 
             y0: T::default(),
             y1: T::default(),
             y2: T::default(),
+
+            input_buffer: vec![],
+            cutoff_buffer: vec![],
+            q_buffer: vec![],
+            parameters: vec![],
+            tail: Tail::default(),
         }
     }
 
-    fn parameters(&mut self, env: &Env, samples: usize) -> Vec<(f64, f64, f64)> {
-        let mut cutoff: Vec<f64> = vec![0.0; samples];
-        self.cutoff.fill_buffer(env, &mut cutoff, samples);
-        let mut q: Vec<f64> = vec![0.0; samples];
-        self.q.fill_buffer(env, &mut q, samples);
+    fn parameters(&mut self, env: &Env, samples: usize) {
+        let cutoff = scratch(&mut self.cutoff_buffer, samples);
+        self.cutoff.fill_buffer(env, cutoff, samples);
+        let q = scratch(&mut self.q_buffer, samples);
+        self.q.fill_buffer(env, q, samples);
 
-        let mut result = vec![(0.0, 0.0, 0.0); samples];
+        let result = scratch(&mut self.parameters, samples);
         for i in 0..samples {
             let q = q[i];
             let cutoff = cutoff[i];
             if (cutoff != self.cached_cutoff) | (q != self.cached_q) {
                 self.cached_cutoff = cutoff;
                 self.cached_q = q;
-                let pfreq = PI * cutoff / 44100.0;
+                let pfreq = PI * cutoff / env.sample_rate as f64;
                 let d = pfreq.tan();
                 let c = (1.0 - d) / (1.0 + d);
                 let cosf = pfreq.cos();
@@ -71,22 +86,20 @@ impl<'a, T: Copy + Default> RLPF<'a, T> {
             }
             result[i] = (self.a0, self.b1, self.b2);
         }
-        result
     }
 }
 
-impl<'a, T: Copy + Default + Add<Output = T> + Mul<Output = T> + From<f64>> ValueNode
-    for RLPF<'a, T>
+impl<'a, T: Frame + Add<Output = T> + Mul<Output = T> + From<f64>> ValueNode for RLPF<'a, T>
 {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let parameters = self.parameters(env, samples);
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
+        self.parameters(env, samples);
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
 
         for i in 0..samples {
             let v0 = input[i];
-            let (a0, b1, b2) = parameters[i];
+            let (a0, b1, b2) = self.parameters[i];
 
             self.y0 = v0 * a0.into() + self.y1 * b1.into() + self.y2 * b2.into();
             let out = self.y0 + self.y1 * 2.0.into() + self.y2;
@@ -94,14 +107,34 @@ impl<'a, T: Copy + Default + Add<Output = T> + Mul<Output = T> + From<f64>> Valu
             self.y1 = self.y0;
             buffer[i] = out;
         }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], FILTER_TAIL);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.cutoff_buffer, max_block_size);
+        scratch(&mut self.q_buffer, max_block_size);
+        scratch(&mut self.parameters, max_block_size);
+        self.input.prepare(max_block_size);
+        self.cutoff.prepare(max_block_size);
+        self.q.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
     }
 }
 
 pub struct AllPass<'a, T> {
     input: Value<'a, T>,
     k: T,
+    delay: f64,
+    sample_rate: u32,
 
     buff: VecDeque<T>,
+    input_buffer: Vec<T>,
+    tail: Tail,
 }
 
 impl<'a, T: From<f64>> AllPass<'a, T> {
@@ -110,21 +143,28 @@ impl<'a, T: From<f64>> AllPass<'a, T> {
         AllPass {
             input: input.into(),
             k: k.into(),
+            delay,
+            sample_rate: 0,
 
-            buff: (0..(44100.0 * delay) as usize)
-                .map(|_| 0.0.into())
-                .collect(),
+            buff: VecDeque::new(),
+            input_buffer: vec![],
+            tail: Tail::default(),
         }
     }
 }
 
-impl<'a, T: Copy + Add<Output = T> + Mul<Output = T> + Neg<Output = T> + Default> ValueNode
+impl<'a, T: Frame + Add<Output = T> + Mul<Output = T> + Neg<Output = T>> ValueNode
     for AllPass<'a, T>
 {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            let len = ((env.sample_rate as f64 * self.delay) as usize).max(2);
+            self.buff.resize_with(len, T::default);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
 
         for i in 0..samples {
             let s_d = self.buff.pop_front().unwrap_or_else(|| T::default());
@@ -133,6 +173,21 @@ impl<'a, T: Copy + Add<Output = T> + Mul<Output = T> + Neg<Output = T> + Default
             self.buff.push_back(s);
             buffer[i] = y;
         }
+        // Anything still circulating in the delay line has to come out before we're done
+        self.tail.update(
+            self.input.is_finished(),
+            &buffer[0..samples],
+            self.buff.len() + FILTER_TAIL,
+        );
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
     }
 }
 
@@ -159,6 +214,12 @@ pub struct TrapezoidSVF<'a, T> {
 
     ic1eq: T,
     ic2eq: T,
+
+    input_buffer: Vec<T>,
+    frequency_buffer: Vec<f64>,
+    q_buffer: Vec<f64>,
+    parameters: Vec<(f64, f64, f64, f64)>,
+    tail: Tail,
 }
 
 //From: http://www.cytomic.com/files/dsp/SvfLinearTrapOptimised2.pdf
@@ -173,26 +234,32 @@ impl<'a, T: Zero> TrapezoidSVF<'a, T> {
             input: input.into(),
             frequency: frequency.into(),
             q: q.into(),
-            cached_q: std::f64::NAN,
-            cached_frequency: std::f64::NAN,
-            filter_type: filter_type,
-            k: std::f64::NAN,
-            a1: std::f64::NAN,
-            a2: std::f64::NAN,
-            a3: std::f64::NAN,
+            cached_q: f64::NAN,
+            cached_frequency: f64::NAN,
+            filter_type,
+            k: f64::NAN,
+            a1: f64::NAN,
+            a2: f64::NAN,
+            a3: f64::NAN,
 
             ic1eq: T::zero(),
             ic2eq: T::zero(),
+
+            input_buffer: vec![],
+            frequency_buffer: vec![],
+            q_buffer: vec![],
+            parameters: vec![],
+            tail: Tail::default(),
         }
     }
 
-    fn parameters(&mut self, env: &Env, samples: usize) -> Vec<(f64, f64, f64, f64)> {
-        let mut frequency: Vec<f64> = vec![0.0; samples];
-        self.frequency.fill_buffer(env, &mut frequency, samples);
-        let mut q: Vec<f64> = vec![0.0; samples];
-        self.q.fill_buffer(env, &mut q, samples);
+    fn parameters(&mut self, env: &Env, samples: usize) {
+        let frequency = scratch(&mut self.frequency_buffer, samples);
+        self.frequency.fill_buffer(env, frequency, samples);
+        let q = scratch(&mut self.q_buffer, samples);
+        self.q.fill_buffer(env, q, samples);
 
-        let mut result = vec![(0.0, 0.0, 0.0, 0.0); samples];
+        let result = scratch(&mut self.parameters, samples);
         for i in 0..samples {
             let frequency = frequency[i];
             let q = q[i];
@@ -210,7 +277,6 @@ impl<'a, T: Zero> TrapezoidSVF<'a, T> {
 
             result[i] = (self.k, self.a1, self.a2, self.a3);
         }
-        result
     }
 
     pub fn low_pass(
@@ -262,18 +328,18 @@ impl<'a, T: Zero> TrapezoidSVF<'a, T> {
     }
 }
 
-impl<'a, T: Copy + Zero + Default + Sub<Output = T> + Mul<Output = T> + From<f64>> ValueNode
+impl<'a, T: Frame + Zero + Sub<Output = T> + Mul<Output = T> + From<f64>> ValueNode
     for TrapezoidSVF<'a, T>
 {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::zero()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
-        let parameters = self.parameters(env, samples);
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        self.parameters(env, samples);
 
-        for i in 0..samples {
-            let (k, a1, a2, a3) = parameters[i];
-            let v0 = input[i];
+        for (i, out) in buffer[0..samples].iter_mut().enumerate() {
+            let (k, a1, a2, a3) = self.parameters[i];
+            let v0 = self.input_buffer[i];
 
             let v3 = v0 - self.ic2eq;
             let v1 = self.ic1eq * a1.into() + v3 * a2.into();
@@ -281,7 +347,7 @@ impl<'a, T: Copy + Zero + Default + Sub<Output = T> + Mul<Output = T> + From<f64
             self.ic1eq = v1 * 2.0.into() - self.ic1eq;
             self.ic2eq = v2 * 2.0.into() - self.ic2eq;
 
-            buffer[i] = match self.filter_type {
+            *out = match self.filter_type {
                 FilterType::Low => v2,
                 FilterType::Band => v1,
                 FilterType::High => v0 - v1 * k.into() - v2,
@@ -290,6 +356,22 @@ impl<'a, T: Copy + Zero + Default + Sub<Output = T> + Mul<Output = T> + From<f64
                 FilterType::All => v0 - v1 * (k * 2.0).into(),
             };
         }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], FILTER_TAIL);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        scratch(&mut self.frequency_buffer, max_block_size);
+        scratch(&mut self.q_buffer, max_block_size);
+        scratch(&mut self.parameters, max_block_size);
+        self.input.prepare(max_block_size);
+        self.frequency.prepare(max_block_size);
+        self.q.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
     }
 }
 
@@ -298,15 +380,22 @@ where
     D: ValueNode,
 {
     input: D,
+    delay: f64,
+    sample_rate: u32,
     buffer: VecDeque<D::T>,
+    input_buffer: Vec<D::T>,
+    finished_samples: usize,
 }
 
 impl<T: Default, D: ValueNode<T = T>> Comb<D> {
     pub fn new(input: D, delay: f64) -> Self {
-        let len = delay * 44100.0;
         Self {
             input,
-            buffer: (0..len as usize).map(|_| T::default()).collect(),
+            delay,
+            sample_rate: 0,
+            buffer: VecDeque::new(),
+            input_buffer: vec![],
+            finished_samples: 0,
         }
     }
 }
@@ -314,12 +403,31 @@ impl<T: Default, D: ValueNode<T = T>> Comb<D> {
 impl<T: Add<Output = T> + Copy + Default, D: ValueNode<T = T>> ValueNode for Comb<D> {
     type T = D::T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
-        let mut input: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.input.fill_buffer(env, &mut input, samples);
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            let len = (env.sample_rate as f64 * self.delay) as usize;
+            self.buffer.resize_with(len, T::default);
+        }
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
 
         for (i, v0) in input.iter().enumerate() {
             self.buffer.push_back(*v0);
             buffer[i] = *v0 + self.buffer.pop_front().unwrap();
         }
+        if self.input.is_finished() {
+            self.finished_samples += samples;
+        } else {
+            self.finished_samples = 0;
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.input.is_finished() && self.finished_samples >= self.buffer.len()
     }
 }
@@ -8,7 +8,9 @@ pub mod envelope;
 pub mod filter;
 pub mod note;
 pub mod oscillator;
+pub mod render;
 pub mod sequence;
+pub mod spectral;
 pub mod value;
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,492 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use minimp3::{Decoder, Error, Frame as Mp3Frame};
+
+use crate::{oscillator::resample::resample, value::Frame};
+
+#[derive(Clone, Debug)]
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    pub original_sample_rate: u32,
+    pub channels: Vec<Vec<f64>>,
+}
+
+impl DecodedAudio {
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, |c| c.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn resampled(&self, sample_rate: u32) -> DecodedAudio {
+        DecodedAudio {
+            sample_rate,
+            original_sample_rate: self.original_sample_rate,
+            channels: self
+                .channels
+                .iter()
+                .map(|c| resample(c, self.sample_rate, sample_rate))
+                .collect(),
+        }
+    }
+
+    pub fn frame<T: Frame>(&self, idx: usize) -> T {
+        let mut frame = [0.0; 8];
+        let count = self.channels.len().min(frame.len());
+        for (c, channel) in self.channels.iter().take(count).enumerate() {
+            frame[c] = channel[idx];
+        }
+        T::from_channels(&frame[0..count])
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    Malformed(String),
+    Unsupported(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "{}", e),
+            DecodeError::Malformed(message) => write!(f, "malformed audio: {}", message),
+            DecodeError::Unsupported(message) => write!(f, "unsupported audio: {}", message),
+        }
+    }
+}
+
+impl StdError for DecodeError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Unsigned8,
+    Signed8,
+    Signed16,
+    Signed24,
+    Signed32,
+    Float32,
+    Float64,
+}
+
+impl Encoding {
+    fn bytes(self) -> usize {
+        match self {
+            Encoding::Unsigned8 | Encoding::Signed8 => 1,
+            Encoding::Signed16 => 2,
+            Encoding::Signed24 => 3,
+            Encoding::Signed32 | Encoding::Float32 => 4,
+            Encoding::Float64 => 8,
+        }
+    }
+
+    fn decode(self, bytes: &[u8], big_endian: bool) -> f64 {
+        let mut b = [0u8; 8];
+        let n = self.bytes();
+        if big_endian {
+            for i in 0..n {
+                b[i] = bytes[n - 1 - i];
+            }
+        } else {
+            b[0..n].copy_from_slice(&bytes[0..n]);
+        }
+        match self {
+            Encoding::Unsigned8 => (b[0] as f64 - 128.0) / 128.0,
+            Encoding::Signed8 => b[0] as i8 as f64 / 128.0,
+            Encoding::Signed16 => i16::from_le_bytes([b[0], b[1]]) as f64 / 32768.0,
+            Encoding::Signed24 => {
+                (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f64 / 8_388_608.0
+            }
+            Encoding::Signed32 => {
+                i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64 / 2_147_483_648.0
+            }
+            Encoding::Float32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Encoding::Float64 => f64::from_le_bytes(b),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RawFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub encoding: Encoding,
+    pub big_endian: bool,
+}
+
+fn invalid(message: &str) -> DecodeError {
+    DecodeError::Malformed(message.to_string())
+}
+
+fn deinterleave(data: &[u8], format: RawFormat) -> Result<DecodedAudio, DecodeError> {
+    if format.channels == 0 {
+        return Err(invalid("audio has no channels"));
+    }
+    let stride = format.channels * format.encoding.bytes();
+    let frames = data.len() / stride;
+    let mut channels: Vec<Vec<f64>> = (0..format.channels)
+        .map(|_| Vec::with_capacity(frames))
+        .collect();
+    for frame in data.chunks_exact(stride) {
+        for (c, sample) in frame.chunks_exact(format.encoding.bytes()).enumerate() {
+            channels[c].push(format.encoding.decode(sample, format.big_endian));
+        }
+    }
+    Ok(DecodedAudio {
+        sample_rate: format.sample_rate,
+        original_sample_rate: format.sample_rate,
+        channels,
+    })
+}
+
+pub fn decode_raw(data: &[u8], format: RawFormat) -> Result<DecodedAudio, DecodeError> {
+    deinterleave(data, format)
+}
+
+fn u16_le(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_le(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn u16_be(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn u32_be(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+pub fn decode_wav(data: &[u8]) -> Result<DecodedAudio, DecodeError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF/WAVE file"));
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32_le(data, pos + 4) as usize;
+        let body = &data[pos + 8..(pos + 8).saturating_add(size).min(data.len())];
+        if id == b"fmt " {
+            if body.len() < 16 {
+                return Err(invalid("truncated WAV fmt chunk"));
+            }
+            let mut tag = u16_le(body, 0);
+            let channels = u16_le(body, 2) as usize;
+            let sample_rate = u32_le(body, 4);
+            let block_align = u16_le(body, 12) as usize;
+            let bits = u16_le(body, 14);
+            // WAVE_FORMAT_EXTENSIBLE keeps the real format tag at the start of the GUID
+            if tag == 0xFFFE && body.len() >= 26 {
+                tag = u16_le(body, 24);
+            }
+            let container = block_align.checked_div(channels).unwrap_or(0);
+            let encoding = match (tag, container, bits) {
+                (1, 1, _) => Encoding::Unsigned8,
+                (1, 2, _) => Encoding::Signed16,
+                (1, 3, _) => Encoding::Signed24,
+                (1, 4, _) => Encoding::Signed32,
+                (3, 4, 32) => Encoding::Float32,
+                (3, 8, 64) => Encoding::Float64,
+                _ => {
+                    return Err(DecodeError::Unsupported(format!(
+                        "WAV encoding with format tag {} and {} bits",
+                        tag, bits
+                    )))
+                }
+            };
+            format = Some(RawFormat {
+                sample_rate,
+                channels,
+                encoding,
+                big_endian: false,
+            });
+        } else if id == b"data" {
+            let format = format.ok_or_else(|| invalid("WAV data chunk before fmt chunk"))?;
+            return deinterleave(body, format);
+        }
+        pos += 8 + size + (size & 1);
+    }
+    Err(invalid("WAV file has no data chunk"))
+}
+
+// 80 bit IEEE 754 extended precision, used by AIFF for the sample rate
+fn extended_be(data: &[u8], at: usize) -> f64 {
+    let exponent = (u16_be(data, at) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes([
+        data[at + 2],
+        data[at + 3],
+        data[at + 4],
+        data[at + 5],
+        data[at + 6],
+        data[at + 7],
+        data[at + 8],
+        data[at + 9],
+    ]);
+    if exponent == 0 && mantissa == 0 {
+        0.0
+    } else {
+        mantissa as f64 * 2.0f64.powi(exponent - 16383 - 63)
+    }
+}
+
+pub fn decode_aiff(data: &[u8]) -> Result<DecodedAudio, DecodeError> {
+    if data.len() < 12 || &data[0..4] != b"FORM" {
+        return Err(invalid("not an AIFF file"));
+    }
+    let compressed = match &data[8..12] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(invalid("not an AIFF file")),
+    };
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32_be(data, pos + 4) as usize;
+        let body = &data[pos + 8..(pos + 8).saturating_add(size).min(data.len())];
+        if id == b"COMM" {
+            if body.len() < 18 || (compressed && body.len() < 22) {
+                return Err(invalid("truncated AIFF COMM chunk"));
+            }
+            let channels = u16_be(body, 0) as usize;
+            let bits = u16_be(body, 6);
+            let sample_rate = extended_be(body, 8).round() as u32;
+            let compression: &[u8] = if compressed { &body[18..22] } else { b"NONE" };
+            let (encoding, big_endian) = match (compression, bits.div_ceil(8)) {
+                (b"NONE", 1) | (b"twos", 1) | (b"sowt", 1) => (Encoding::Signed8, true),
+                (b"NONE", 2) | (b"twos", 2) => (Encoding::Signed16, true),
+                (b"NONE", 3) | (b"in24", 3) => (Encoding::Signed24, true),
+                (b"NONE", 4) | (b"in32", 4) => (Encoding::Signed32, true),
+                (b"sowt", 2) => (Encoding::Signed16, false),
+                (b"sowt", 3) => (Encoding::Signed24, false),
+                (b"sowt", 4) => (Encoding::Signed32, false),
+                (b"fl32", _) | (b"FL32", _) => (Encoding::Float32, true),
+                (b"fl64", _) | (b"FL64", _) => (Encoding::Float64, true),
+                (compression, _) => {
+                    return Err(DecodeError::Unsupported(format!(
+                        "AIFF compression {}",
+                        String::from_utf8_lossy(compression)
+                    )))
+                }
+            };
+            format = Some(RawFormat {
+                sample_rate,
+                channels,
+                encoding,
+                big_endian,
+            });
+        } else if id == b"SSND" {
+            let format = format.ok_or_else(|| invalid("AIFF SSND chunk before COMM chunk"))?;
+            if body.len() < 8 {
+                return Err(invalid("truncated AIFF SSND chunk"));
+            }
+            let offset = (8 + u32_be(body, 0) as usize).min(body.len());
+            return deinterleave(&body[offset..], format);
+        }
+        pos += 8 + size + (size & 1);
+    }
+    Err(invalid("AIFF file has no SSND chunk"))
+}
+
+pub fn decode_mp3(reader: impl Read) -> Result<DecodedAudio, DecodeError> {
+    let mut decoder = Decoder::new(reader);
+    let mut audio: Option<DecodedAudio> = None;
+    loop {
+        match decoder.next_frame() {
+            Ok(Mp3Frame {
+                data,
+                sample_rate,
+                channels,
+                ..
+            }) => {
+                let audio = audio.get_or_insert_with(|| DecodedAudio {
+                    sample_rate: sample_rate as u32,
+                    original_sample_rate: sample_rate as u32,
+                    channels: vec![vec![]; channels],
+                });
+                if audio.channels.len() != channels || audio.sample_rate != sample_rate as u32 {
+                    return Err(invalid("MP3 stream changes format part way through"));
+                }
+                // Half of full scale, which is the level MP3 samples have always been
+                // played at
+                for frame in data.chunks_exact(channels) {
+                    for (c, s) in frame.iter().enumerate() {
+                        audio.channels[c].push(*s as f64 / 65536.0);
+                    }
+                }
+            }
+            Err(Error::SkippedData) => continue,
+            Err(Error::Eof) => break,
+            Err(Error::Io(e)) => return Err(DecodeError::Io(e)),
+            Err(e) => return Err(invalid(&format!("{:?}", e))),
+        }
+    }
+    audio.ok_or_else(|| invalid("MP3 stream has no frames"))
+}
+
+// Picks a decoder from the file's magic bytes, falling back to the extension for MP3
+pub fn decode_file(path: impl AsRef<Path>) -> Result<DecodedAudio, DecodeError> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        decode_wav(&data)
+    } else if data.len() >= 12 && &data[0..4] == b"FORM" {
+        decode_aiff(&data)
+    } else if data.starts_with(b"ID3")
+        || path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("mp3"))
+    {
+        decode_mp3(&data[..])
+    } else {
+        Err(DecodeError::Unsupported(
+            "not a WAV, AIFF or MP3 file".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        render::{write_wav, SampleFormat},
+        value::{MultiSample, Value, ValueNode},
+        Env,
+    };
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    struct Steps;
+
+    impl ValueNode for Steps {
+        type T = MultiSample<f64>;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for (i, b) in buffer[0..samples].iter_mut().enumerate() {
+                let v = [0.0, 0.5, -0.5, 0.25][i % 4];
+                *b = MultiSample(v, -v);
+            }
+        }
+    }
+
+    fn rendered_wav(format: SampleFormat) -> Vec<u8> {
+        let mut env = Env::new(8000);
+        let mut value: Value<MultiSample<f64>> = Steps.into();
+        let mut out = Cursor::new(vec![]);
+        write_wav(
+            &mut out,
+            &mut value,
+            &mut env,
+            Duration::from_millis(10),
+            format,
+        )
+        .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn decodes_rendered_wav() {
+        for format in &[
+            SampleFormat::Pcm16,
+            SampleFormat::Pcm24,
+            SampleFormat::Float32,
+        ] {
+            let audio = decode_wav(&rendered_wav(*format)).unwrap();
+            assert_eq!(audio.sample_rate, 8000);
+            assert_eq!(audio.channels.len(), 2);
+            assert_eq!(audio.len(), 80);
+            for i in 0..audio.len() {
+                let v = [0.0, 0.5, -0.5, 0.25][i % 4];
+                assert!((audio.channels[0][i] - v).abs() < 1e-4);
+                assert!((audio.channels[1][i] + v).abs() < 1e-4);
+            }
+        }
+    }
+
+    fn aiff(compression: Option<&[u8; 4]>, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let mut comm = vec![];
+        comm.extend_from_slice(&1u16.to_be_bytes());
+        let frames = samples.len() / (bits as usize / 8);
+        comm.extend_from_slice(&(frames as u32).to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        // 44100 as an 80 bit extended float
+        comm.extend_from_slice(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        if let Some(compression) = compression {
+            comm.extend_from_slice(compression);
+            comm.push(0);
+            comm.push(0);
+        }
+        let mut body = vec![];
+        body.extend_from_slice(if compression.is_some() {
+            b"AIFC"
+        } else {
+            b"AIFF"
+        });
+        body.extend_from_slice(b"COMM");
+        body.extend_from_slice(&(comm.len() as u32).to_be_bytes());
+        body.extend_from_slice(&comm);
+        body.extend_from_slice(b"SSND");
+        body.extend_from_slice(&(samples.len() as u32 + 8).to_be_bytes());
+        body.extend_from_slice(&[0; 8]);
+        body.extend_from_slice(samples);
+        let mut data = b"FORM".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    #[test]
+    fn decodes_aiff() {
+        let audio = decode_aiff(&aiff(None, 16, &[0x40, 0x00, 0xc0, 0x00])).unwrap();
+        assert_eq!(audio.sample_rate, 44100);
+        assert_eq!(audio.channels, vec![vec![0.5, -0.5]]);
+
+        let audio = decode_aiff(&aiff(Some(b"sowt"), 16, &[0x00, 0x40, 0x00, 0xc0])).unwrap();
+        assert_eq!(audio.channels, vec![vec![0.5, -0.5]]);
+
+        assert!(decode_aiff(&aiff(Some(b"ulaw"), 8, &[0])).is_err());
+    }
+
+    #[test]
+    fn decodes_raw() {
+        let format = RawFormat {
+            sample_rate: 100,
+            channels: 2,
+            encoding: Encoding::Unsigned8,
+            big_endian: false,
+        };
+        let audio = decode_raw(&[128, 192, 64, 0], format).unwrap();
+        assert_eq!(audio.channels, vec![vec![0.0, -0.5], vec![0.5, -1.0]]);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(decode_wav(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(decode_wav(b"not audio").is_err());
+        assert!(decode_mp3(&b""[..]).is_err());
+    }
+}
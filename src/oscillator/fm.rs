@@ -0,0 +1,192 @@
+use std::f64::consts::PI;
+
+use crate::{
+    value::{scratch, Value, ValueNode},
+    Env,
+};
+
+// One sine operator of an FmVoice. Its frequency is the voice frequency times `ratio` and
+// its output is scaled by `level`, both of which can be envelopes.
+pub struct Operator<'a> {
+    ratio: Value<'a, f64>,
+    level: Value<'a, f64>,
+    feedback: f64,
+
+    ratio_buffer: Vec<f64>,
+    level_buffer: Vec<f64>,
+    phase: f64,
+    // The last two outputs, averaged for feedback to keep it from oscillating at nyquist
+    previous: [f64; 2],
+}
+
+impl<'a> Operator<'a> {
+    pub fn new(ratio: impl Into<Value<'a, f64>>) -> Self {
+        Self {
+            ratio: ratio.into(),
+            level: 1.0.into(),
+            feedback: 0.0,
+
+            ratio_buffer: vec![],
+            level_buffer: vec![],
+            phase: 0.0,
+            previous: [0.0; 2],
+        }
+    }
+
+    // Defaults to a constant 1.0, which never finishes, so a voice only reports being finished
+    // once every carrier has been given a level that does, such as an envelope
+    pub fn level(mut self, level: impl Into<Value<'a, f64>>) -> Self {
+        self.level = level.into();
+        self
+    }
+
+    // Modulation of the operator by its own output, in radians per unit of output
+    pub fn feedback(mut self, feedback: f64) -> Self {
+        self.feedback = feedback;
+        self
+    }
+}
+
+// DX style FM (really phase modulation) voice. `matrix[to][from]` is how many radians of
+// phase modulation operator `from` applies to operator `to` per unit of its output, and
+// `outputs[i]` is how much of operator `i` is heard. Operators are run from the last to the
+// first, so modulators should have higher indices than the operators they modulate; any
+// modulation going the other way lags by a sample.
+pub struct FmVoice<'a> {
+    frequency: Value<'a, f64>,
+    operators: Vec<Operator<'a>>,
+    matrix: Vec<Vec<f64>>,
+    outputs: Vec<f64>,
+
+    frequency_buffer: Vec<f64>,
+    current: Vec<f64>,
+}
+
+impl<'a> FmVoice<'a> {
+    pub fn new(frequency: impl Into<Value<'a, f64>>) -> Self {
+        Self {
+            frequency: frequency.into(),
+            operators: vec![],
+            matrix: vec![],
+            outputs: vec![],
+
+            frequency_buffer: vec![],
+            current: vec![],
+        }
+    }
+
+    // Adds an operator, which is silent and unconnected until it is routed with `modulate`
+    // or `output`
+    pub fn operator(mut self, operator: Operator<'a>) -> Self {
+        self.operators.push(operator);
+        let count = self.operators.len();
+        for row in &mut self.matrix {
+            row.resize(count, 0.0);
+        }
+        self.matrix.push(vec![0.0; count]);
+        self.outputs.push(0.0);
+        self.current.push(0.0);
+        self
+    }
+
+    pub fn modulate(mut self, from: usize, to: usize, depth: f64) -> Self {
+        self.matrix[to][from] = depth;
+        self
+    }
+
+    pub fn output(mut self, operator: usize, level: f64) -> Self {
+        self.outputs[operator] = level;
+        self
+    }
+
+    // Replaces the whole routing. Missing entries count as zero.
+    pub fn algorithm(mut self, matrix: Vec<Vec<f64>>, outputs: Vec<f64>) -> Self {
+        let count = self.operators.len();
+        for (to, row) in self.matrix.iter_mut().enumerate() {
+            for (from, depth) in row.iter_mut().enumerate() {
+                *depth = matrix
+                    .get(to)
+                    .and_then(|r| r.get(from))
+                    .cloned()
+                    .unwrap_or(0.0);
+            }
+        }
+        self.outputs = (0..count)
+            .map(|i| outputs.get(i).cloned().unwrap_or(0.0))
+            .collect();
+        self
+    }
+
+    // A chain where each operator modulates the one before it and only the first is heard
+    pub fn stack(frequency: impl Into<Value<'a, f64>>, operators: Vec<Operator<'a>>) -> Self {
+        let count = operators.len();
+        let mut voice = operators
+            .into_iter()
+            .fold(Self::new(frequency), |voice, op| voice.operator(op));
+        for i in 1..count {
+            voice = voice.modulate(i, i - 1, 1.0);
+        }
+        if count > 0 {
+            voice = voice.output(0, 1.0);
+        }
+        voice
+    }
+}
+
+impl<'a> ValueNode for FmVoice<'a> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let frequency = scratch(&mut self.frequency_buffer, samples);
+        self.frequency.fill_buffer(env, frequency, samples);
+        for op in &mut self.operators {
+            let ratio = scratch(&mut op.ratio_buffer, samples);
+            op.ratio.fill_buffer(env, ratio, samples);
+            let level = scratch(&mut op.level_buffer, samples);
+            op.level.fill_buffer(env, level, samples);
+        }
+
+        let step = 1.0 / env.sample_rate as f64;
+        for (i, output) in buffer[0..samples].iter_mut().enumerate() {
+            let mut sample = 0.0;
+            for to in (0..self.operators.len()).rev() {
+                let mut modulation = 0.0;
+                for (from, depth) in self.matrix[to].iter().enumerate() {
+                    if from != to {
+                        modulation += depth * self.current[from];
+                    }
+                }
+                let op = &mut self.operators[to];
+                let previous = (op.previous[0] + op.previous[1]) / 2.0;
+                modulation += (op.feedback + self.matrix[to][to]) * previous;
+
+                let out = (op.phase * PI * 2.0 + modulation).sin() * op.level_buffer[i];
+                op.phase += self.frequency_buffer[i] * op.ratio_buffer[i] * step;
+                op.phase -= op.phase.floor();
+                op.previous = [out, op.previous[0]];
+                self.current[to] = out;
+                sample += out * self.outputs[to];
+            }
+            *output = sample;
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.frequency_buffer, max_block_size);
+        self.frequency.prepare(max_block_size);
+        for op in &mut self.operators {
+            scratch(&mut op.ratio_buffer, max_block_size);
+            scratch(&mut op.level_buffer, max_block_size);
+            op.ratio.prepare(max_block_size);
+            op.level.prepare(max_block_size);
+        }
+    }
+
+    // Done once the levels of every operator that is heard have finished. Modulators are
+    // ignored, and a carrier left at the default level keeps the voice going forever.
+    fn is_finished(&self) -> bool {
+        self.operators
+            .iter()
+            .zip(&self.outputs)
+            .all(|(op, output)| *output == 0.0 || op.level.is_finished())
+    }
+}
@@ -1,134 +1,432 @@
+pub mod decode;
+pub mod fm;
+pub mod resample;
 pub mod sampler;
 pub mod string;
+pub mod unison;
+pub mod wavetable;
 
 use rand::Rng;
+use std::cell::RefCell;
 use std::f64::consts::PI;
+use std::rc::Rc;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 
 use super::{
-    value::{Value, ValueNode},
+    oscillator::wavetable::WaveTable,
+    value::{scratch, Value, ValueNode},
     Env,
 };
 
 lazy_static! {
-    static ref SINE: Vec<(f64, Vec<f64>)> = {
-        let len = 512;
-        vec![(
-            2200.0,
-            (0..len)
-                .map(|i| (i as f64 * ((PI * 2.0) / len as f64)).sin())
-                .collect(),
-        )]
-    };
-    static ref SQUARE: Vec<(f64, Vec<f64>)> = {
-        let len = 512;
-        vec![(
-            22000.0,
-            (0..len)
-                .map(|i| {
-                    if i as f64 * ((PI * 2.0) / len as f64).sin() > 0.0 {
-                        1.0
-                    } else {
-                        -1.0
-                    }
-                })
-                .collect(),
-        )]
-    };
-    static ref SAW_BL: Vec<(f64, Vec<f64>)> = {
-        let mut tables = vec![];
-        let len = 512;
-        let f = 1.0;
-        let mut max_f = f * 15.0;
-        while max_f <= 22050.0 {
-            let mut table = vec![0.0; len];
-            let mut partial = f;
-            let mut pi = 1;
-            while partial < max_f {
-                let a = 1.0 / pi as f64;
-                for i in 0..len {
-                    table[i] += (((2.0 * PI) / len as f64) * partial * i as f64).sin() * a * 0.5;
-                }
-                partial += f;
-                pi += 1;
-            }
-            let tf = (44100.0 * 44100.0) / (2.0 * max_f * len as f64);
-            tables.push((tf, table.iter().rev().cloned().collect()));
-            max_f *= 2.0;
+    static ref SINE: WaveTable = WaveTable::from_partials(&[1.0]);
+    static ref SQUARE_BL: WaveTable = WaveTable::from_harmonic_series(|n| {
+        if n % 2 == 1 {
+            4.0 / (PI * n as f64)
+        } else {
+            0.0
         }
-        tables.sort_by_key(|t| (t.0 * 1000.0) as u32);
-        tables
-    };
-    static ref SAW: Vec<(f64, Vec<f64>)> = {
-        let len = 2048;
-        let mut table = Vec::with_capacity(len);
-        for i in 0..len {
-            let v = -1.0 + 2.0 * (i as f64 / len as f64);
-            eprintln!("{}", v);
-            table.push(v);
+    });
+    static ref TRIANGLE_BL: WaveTable = WaveTable::from_harmonic_series(|n| {
+        if n % 2 == 1 {
+            let sign = if n % 4 == 1 { 1.0 } else { -1.0 };
+            sign * 8.0 / (PI * PI * (n * n) as f64)
+        } else {
+            0.0
         }
-        vec![(22000.0, table)]
-    };
+    });
+    static ref SAW_BL: WaveTable = WaveTable::from_harmonic_series(|n| -0.5 / n as f64);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TableInterpolation {
+    // Nearest sample below the read position. Cheapest, and noisy with short tables.
+    Truncate,
+    Linear,
+    // Cubic Hermite through the four surrounding samples
+    Cubic,
+}
+
+impl TableInterpolation {
+    fn read(self, table: &[f64], phase: f64) -> f64 {
+        let len = table.len();
+        let position = phase * len as f64;
+        let i = position as usize % len;
+        let f = position - position.floor();
+        match self {
+            TableInterpolation::Truncate => table[i],
+            TableInterpolation::Linear => {
+                let (a, b) = (table[i], table[(i + 1) % len]);
+                a + (b - a) * f
+            }
+            TableInterpolation::Cubic => {
+                let (xm1, x0, x1, x2) = (
+                    table[(i + len - 1) % len],
+                    table[i],
+                    table[(i + 1) % len],
+                    table[(i + 2) % len],
+                );
+                let c1 = (x1 - xm1) * 0.5;
+                let c2 = xm1 - x0 * 2.5 + x1 * 2.0 - x2 * 0.5;
+                let c3 = (x2 - xm1) * 0.5 + (x0 - x1) * 1.5;
+                ((c3 * f + c2) * f + c1) * f + x0
+            }
+        }
+    }
 }
 
 pub struct WaveTableSynth<'a, T> {
     frequency: Value<'a, T>,
-    tables: Vec<(f64, Vec<f64>)>,
-    position: f64,
+    frequency_buffer: Vec<T>,
+    table: WaveTable,
+    interpolation: TableInterpolation,
+    position: Option<Value<'a, T>>,
+    position_buffer: Vec<T>,
+    phase_modulation: Option<Value<'a, T>>,
+    phase_modulation_buffer: Vec<T>,
+    reset: Option<Value<'a, bool>>,
+    reset_buffer: Vec<bool>,
+    reset_previous: bool,
+    sync: Option<Value<'a, bool>>,
+    sync_buffer: Vec<bool>,
+    // Whether each sample of the last block started a new cycle, for SyncOutput
+    wrap_buffer: Vec<bool>,
+    cycle_started: bool,
+    phase_offset: f64,
+    // Position in the cycle, from 0 to 1, so tables of different lengths can be swapped
+    phase: f64,
 }
 
 impl<'a, T> WaveTableSynth<'a, T> {
-    pub fn sin(frequency: impl Into<Value<'a, T>>) -> Self {
+    pub fn new(frequency: impl Into<Value<'a, T>>, table: WaveTable) -> Self {
         WaveTableSynth {
             frequency: frequency.into(),
-            tables: SINE.to_vec(),
-            position: 0.0,
+            frequency_buffer: vec![],
+            table,
+            interpolation: TableInterpolation::Linear,
+            position: None,
+            position_buffer: vec![],
+            phase_modulation: None,
+            phase_modulation_buffer: vec![],
+            reset: None,
+            reset_buffer: vec![],
+            reset_previous: false,
+            sync: None,
+            sync_buffer: vec![],
+            wrap_buffer: vec![],
+            cycle_started: true,
+            phase_offset: 0.0,
+            phase: 0.0,
         }
     }
 
+    pub fn sin(frequency: impl Into<Value<'a, T>>) -> Self {
+        Self::new(frequency, SINE.clone())
+    }
+
     pub fn square(frequency: impl Into<Value<'a, T>>) -> Self {
-        WaveTableSynth {
-            frequency: frequency.into(),
-            tables: SQUARE.to_vec(),
-            position: 0.0,
-        }
+        Self::new(frequency, SQUARE_BL.clone())
+    }
+
+    pub fn triangle(frequency: impl Into<Value<'a, T>>) -> Self {
+        Self::new(frequency, TRIANGLE_BL.clone())
     }
 
     pub fn saw(frequency: impl Into<Value<'a, T>>) -> Self {
-        WaveTableSynth {
-            frequency: frequency.into(),
-            tables: SAW_BL.to_vec(),
-            position: 0.0,
-        }
+        Self::new(frequency, SAW_BL.clone())
+    }
+
+    pub fn interpolation(mut self, interpolation: TableInterpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    // Where to read from a table with several frames, from 0 for the first to 1 for the
+    // last. Positions between frames crossfade the two either side.
+    pub fn position(mut self, position: impl Into<Value<'a, T>>) -> Self {
+        self.position = Some(position.into());
+        self
+    }
+
+    // Offsets the read position by this many radians without changing the frequency, so a
+    // sine modulator with amplitude I gives a modulation index of I.
+    pub fn phase_modulation(mut self, modulation: impl Into<Value<'a, T>>) -> Self {
+        self.phase_modulation = Some(modulation.into());
+        self
+    }
+
+    // Where in the cycle, from 0 to 1, the oscillator starts and where resets and sync
+    // send it back to
+    pub fn phase(mut self, phase: f64) -> Self {
+        self.phase_offset = phase.rem_euclid(1.0);
+        self.phase = self.phase_offset;
+        self
+    }
+
+    // Restarts the cycle whenever `reset` goes from false to true, such as at note on
+    pub fn reset(mut self, reset: impl Into<Value<'a, bool>>) -> Self {
+        self.reset = Some(reset.into());
+        self
+    }
+
+    // Hard sync: restarts the cycle on every sample where `sync` is true. Usually fed from
+    // another oscillator's SyncOutput.
+    pub fn sync(mut self, sync: impl Into<Value<'a, bool>>) -> Self {
+        self.sync = Some(sync.into());
+        self
+    }
+
+    // Splits the oscillator into its audio and a signal that is true on each sample where
+    // it starts a new cycle, to drive `sync` on other oscillators. Both halves share one
+    // oscillator, which runs once per block whichever half is asked first.
+    pub fn sync_output(self) -> (SyncSource<'a, T>, SyncOutput<'a, T>) {
+        let shared = Rc::new(RefCell::new(SyncShared {
+            synth: self,
+            trigger: None,
+            output: vec![],
+            prepared_block_size: 0,
+        }));
+        (SyncSource(shared.clone()), SyncOutput(shared))
     }
 }
 
 impl<'a, T: Default + Clone + Into<f64> + From<f64>> ValueNode for WaveTableSynth<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
-        let mut frequency: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.frequency.fill_buffer(env, &mut frequency, samples);
+        let frequency = scratch(&mut self.frequency_buffer, samples);
+        self.frequency.fill_buffer(env, frequency, samples);
+        let modulation = scratch(&mut self.phase_modulation_buffer, samples);
+        if let Some(phase_modulation) = &mut self.phase_modulation {
+            phase_modulation.fill_buffer(env, modulation, samples);
+        }
 
+        let position = scratch(&mut self.position_buffer, samples);
+        if let Some(p) = &mut self.position {
+            p.fill_buffer(env, position, samples);
+        }
+        let reset = scratch(&mut self.reset_buffer, samples);
+        if let Some(r) = &mut self.reset {
+            r.fill_buffer(env, reset, samples);
+        }
+        let sync = scratch(&mut self.sync_buffer, samples);
+        if let Some(s) = &mut self.sync {
+            s.fill_buffer(env, sync, samples);
+        }
+        let wraps = scratch(&mut self.wrap_buffer, samples);
+
+        let bands = self.table.bands();
+        let last_frame = self.table.frames().saturating_sub(1);
+        let nyquist = env.sample_rate as f64 / 2.0;
         for i in 0..samples {
-            let mut table = &self.tables[0].1;
+            let restart = sync[i] || (reset[i] && !self.reset_previous);
+            self.reset_previous = reset[i];
+            if restart {
+                self.phase = self.phase_offset;
+            }
+            wraps[i] = restart || self.cycle_started;
             let freq: f64 = frequency[i].clone().into();
-            for (cap, t) in &self.tables {
-                table = t;
-                if cap >= &freq {
-                    break;
-                }
+            let offset: f64 = modulation[i].clone().into();
+            let phase = (self.phase + offset / (PI * 2.0)).rem_euclid(1.0);
+            self.phase += freq / env.sample_rate as f64;
+            self.cycle_started = self.phase >= 1.0 || self.phase < 0.0;
+            self.phase -= self.phase.floor();
+            if self.table.frames() == 0 {
+                buffer[i] = T::default();
+                continue;
             }
 
-            let v = table[self.position as usize];
-            let len = table.len() as f64;
-            self.position += (len / env.sample_rate as f64) * freq;
-            while self.position >= len {
-                self.position -= len;
+            // The richest band that fits under nyquist, going by the size of the frequency so
+            // negative ones are band-limited too. As the frequency climbs towards the top of
+            // its range the next band down is faded in, so timbre changes smoothly rather than
+            // stepping at each band boundary.
+            let speed = freq.abs();
+            let band = bands
+                .iter()
+                .position(|(harmonics, _)| speed * harmonics <= nyquist)
+                .unwrap_or(bands.len() - 1);
+            let mut fade = 0.0;
+            if band + 1 < bands.len() && speed * bands[band].0 <= nyquist {
+                let richer = if band > 0 {
+                    bands[band - 1].0
+                } else {
+                    bands[band].0 * 2.0
+                };
+                let bottom = bands[band].0 / richer;
+                let x = speed * bands[band].0 / nyquist;
+                fade = ((x - bottom) / (1.0 - bottom)).clamp(0.0, 1.0);
             }
+
+            let position: f64 = position[i].clone().into();
+            let position = position.clamp(0.0, 1.0) * last_frame as f64;
+            let frame = (position as usize).min(last_frame);
+            let mix = position - frame as f64;
+            let interpolation = self.interpolation;
+            let read = |frames: &Vec<Vec<f64>>| {
+                let mut v = interpolation.read(&frames[frame], phase);
+                if mix > 0.0 {
+                    v += (interpolation.read(&frames[frame + 1], phase) - v) * mix;
+                }
+                v
+            };
+            let mut v = read(&bands[band].1);
+            if fade > 0.0 {
+                v += (read(&bands[band + 1].1) - v) * fade;
+            }
+            buffer[i] = v.into();
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.frequency_buffer, max_block_size);
+        scratch(&mut self.phase_modulation_buffer, max_block_size);
+        scratch(&mut self.position_buffer, max_block_size);
+        scratch(&mut self.reset_buffer, max_block_size);
+        scratch(&mut self.sync_buffer, max_block_size);
+        scratch(&mut self.wrap_buffer, max_block_size);
+        self.frequency.prepare(max_block_size);
+        if let Some(phase_modulation) = &mut self.phase_modulation {
+            phase_modulation.prepare(max_block_size);
+        }
+        if let Some(position) = &mut self.position {
+            position.prepare(max_block_size);
+        }
+        if let Some(reset) = &mut self.reset {
+            reset.prepare(max_block_size);
+        }
+        if let Some(sync) = &mut self.sync {
+            sync.prepare(max_block_size);
+        }
+    }
+}
+
+struct SyncShared<'a, T> {
+    synth: WaveTableSynth<'a, T>,
+    trigger: Option<(Duration, usize)>,
+    output: Vec<T>,
+    prepared_block_size: usize,
+}
+
+impl<'a, T: Default + Clone + Into<f64> + From<f64>> SyncShared<'a, T> {
+    fn render(&mut self, env: &Env, samples: usize) {
+        if self.trigger != Some((env.time, samples)) {
+            let output = scratch(&mut self.output, samples);
+            self.synth.fill_buffer(env, output, samples);
+            self.trigger = Some((env.time, samples));
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        if self.prepared_block_size < max_block_size {
+            self.prepared_block_size = max_block_size;
+            scratch(&mut self.output, max_block_size);
+            self.synth.prepare(max_block_size);
+        }
+    }
+}
+
+// The audio half of WaveTableSynth::sync_output
+pub struct SyncSource<'a, T>(Rc<RefCell<SyncShared<'a, T>>>);
+
+impl<'a, T: Default + Clone + Into<f64> + From<f64>> ValueNode for SyncSource<'a, T> {
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
+        let mut shared = self.0.borrow_mut();
+        shared.render(env, samples);
+        buffer[0..samples].clone_from_slice(&shared.output[0..samples]);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        self.0.borrow_mut().prepare(max_block_size);
+    }
+}
+
+// The sync half of WaveTableSynth::sync_output
+pub struct SyncOutput<'a, T>(Rc<RefCell<SyncShared<'a, T>>>);
+
+impl<'a, T: Default + Clone + Into<f64> + From<f64>> ValueNode for SyncOutput<'a, T> {
+    type T = bool;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [bool], samples: usize) {
+        let mut shared = self.0.borrow_mut();
+        shared.render(env, samples);
+        buffer[0..samples].copy_from_slice(&shared.synth.wrap_buffer[0..samples]);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        self.0.borrow_mut().prepare(max_block_size);
+    }
+}
+
+// Correction for the step in a naive waveform, spread over the sample either side of a
+// discontinuity. `t` is the phase since the step and `dt` the size of the phase change per
+// sample, always positive. The correction only depends on how far the phase is from the
+// step, pulling the high side down and the low side up, so it is the same whichever way the
+// phase is moving and negative frequencies need no special handling.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Pulse wave with a variable width, kept band-limited with PolyBLEP so the width can be
+// modulated freely. A width of 0.5 is a square wave.
+pub struct Pulse<'a, T> {
+    frequency: Value<'a, T>,
+    width: Value<'a, T>,
+    frequency_buffer: Vec<T>,
+    width_buffer: Vec<T>,
+    phase: f64,
+}
+
+impl<'a, T> Pulse<'a, T> {
+    pub fn new(frequency: impl Into<Value<'a, T>>, width: impl Into<Value<'a, T>>) -> Self {
+        Self {
+            frequency: frequency.into(),
+            width: width.into(),
+            frequency_buffer: vec![],
+            width_buffer: vec![],
+            phase: 0.0,
+        }
+    }
+}
+
+impl<'a, T: Default + Clone + Into<f64> + From<f64>> ValueNode for Pulse<'a, T> {
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
+        let frequency = scratch(&mut self.frequency_buffer, samples);
+        self.frequency.fill_buffer(env, frequency, samples);
+        let width = scratch(&mut self.width_buffer, samples);
+        self.width.fill_buffer(env, width, samples);
+
+        for i in 0..samples {
+            let freq: f64 = frequency[i].clone().into();
+            let width: f64 = width[i].clone().into();
+            let width = width.clamp(0.0, 1.0);
+            let dt = (freq / env.sample_rate as f64).abs().min(0.5);
+
+            let mut v = if self.phase < width { 1.0 } else { -1.0 };
+            v += poly_blep(self.phase, dt);
+            v -= poly_blep((self.phase - width).rem_euclid(1.0), dt);
             buffer[i] = v.into();
+
+            self.phase += freq / env.sample_rate as f64;
+            self.phase -= self.phase.floor();
         }
     }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.frequency_buffer, max_block_size);
+        scratch(&mut self.width_buffer, max_block_size);
+        self.frequency.prepare(max_block_size);
+        self.width.prepare(max_block_size);
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -144,8 +442,8 @@ impl Impulses {
 impl ValueNode for Impulses {
     type T = f64;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
-            buffer[i] = if rand::thread_rng().gen::<f64>() < self.freq / env.sample_rate as f64 {
+        for sample in &mut buffer[0..samples] {
+            *sample = if rand::thread_rng().gen::<f64>() < self.freq / env.sample_rate as f64 {
                 1.0
             } else {
                 0.0
@@ -160,8 +458,8 @@ pub struct WhiteNoise;
 impl ValueNode for WhiteNoise {
     type T = f64;
     fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
-            buffer[i] = rand::thread_rng().gen_range(-1.0, 1.0).into();
+        for sample in &mut buffer[0..samples] {
+            *sample = rand::thread_rng().gen_range(-1.0, 1.0);
         }
     }
 }
@@ -170,6 +468,8 @@ pub struct BrownianNoise<'a, T> {
     current: f64,
     wiggle: Value<'a, T>,
     freq: Value<'a, T>,
+    wiggle_buffer: Vec<T>,
+    freq_buffer: Vec<T>,
 }
 
 impl<'a, T> BrownianNoise<'a, T> {
@@ -178,6 +478,8 @@ impl<'a, T> BrownianNoise<'a, T> {
             current: 0.0,
             wiggle: wiggle.into(),
             freq: freq.into(),
+            wiggle_buffer: vec![],
+            freq_buffer: vec![],
         }
     }
 }
@@ -185,20 +487,55 @@ impl<'a, T> BrownianNoise<'a, T> {
 impl<'a, T: Default + Clone + From<f64> + Into<f64>> ValueNode for BrownianNoise<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut freq: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.freq.fill_buffer(env, &mut freq, samples);
-        let mut wiggle: Vec<T> = (0..samples).map(|_| Self::T::default()).collect();
-        self.wiggle.fill_buffer(env, &mut wiggle, samples);
+        let freq = scratch(&mut self.freq_buffer, samples);
+        self.freq.fill_buffer(env, freq, samples);
+        let wiggle = scratch(&mut self.wiggle_buffer, samples);
+        self.wiggle.fill_buffer(env, wiggle, samples);
 
-        for i in 0..samples {
+        for (i, sample) in buffer[0..samples].iter_mut().enumerate() {
             let wiggle: f64 = wiggle[i].clone().into();
             let freq: f64 = freq[i].clone().into();
             if rand::thread_rng().gen::<f64>() < freq / env.sample_rate as f64 {
                 let wiggle = wiggle.max(0.00001);
-                let step: f64 = rand::thread_rng().gen_range(-wiggle, wiggle).into();
-                self.current = (self.current + step).min(1.0).max(-1.0);
+                let step: f64 = rand::thread_rng().gen_range(-wiggle, wiggle);
+                self.current = (self.current + step).clamp(-1.0, 1.0);
             }
-            buffer[i] = self.current.into();
+            *sample = self.current.into();
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.freq_buffer, max_block_size);
+        scratch(&mut self.wiggle_buffer, max_block_size);
+        self.freq.prepare(max_block_size);
+        self.wiggle.prepare(max_block_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(value: impl Into<Value<'static, f64>>, len: usize) -> Vec<f64> {
+        let mut value = value.into();
+        let env = Env::new(48000);
+        let mut output = vec![0.0; len];
+        value.fill_buffer(&env, &mut output, len);
+        output
+    }
+
+    // Running the phase backwards plays the waveform backwards, so a negative frequency
+    // has to give the positive one reversed, band-limiting included
+    #[test]
+    fn pulse_at_negative_frequency_is_reversed() {
+        // 480Hz repeats every 100 samples
+        let forward = render(Pulse::new(480.0, 0.3), 201);
+        let backward = render(Pulse::new(-480.0, 0.3), 201);
+        for n in 0..=100 {
+            assert!((backward[n] - forward[200 - n]).abs() < 1e-6, "{}", n);
         }
+        // The edges really are smoothed in both directions
+        assert!(forward.iter().any(|v| v.abs() < 0.9));
+        assert!(backward.iter().any(|v| v.abs() < 0.9));
     }
 }
@@ -0,0 +1,142 @@
+use std::f64::consts::PI;
+
+// Number of sinc zero crossings on each side of the kernel, at the narrower of the two rates
+const ZERO_CROSSINGS: f64 = 32.0;
+// Kernel table resolution, in entries per input sample
+const TABLE_RESOLUTION: usize = 512;
+const KAISER_BETA: f64 = 9.0;
+const PASSBAND: f64 = 0.95;
+
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+struct Kernel {
+    half_width: f64,
+    table: Vec<f64>,
+}
+
+impl Kernel {
+    fn new(cutoff: f64) -> Self {
+        let half_width = ZERO_CROSSINGS / cutoff;
+        let len = (half_width * TABLE_RESOLUTION as f64).ceil() as usize + 2;
+        let norm = bessel_i0(KAISER_BETA);
+        let table = (0..len)
+            .map(|i| {
+                let t = i as f64 / TABLE_RESOLUTION as f64;
+                if t >= half_width {
+                    return 0.0;
+                }
+                let x = PI * cutoff * t;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                let w = t / half_width;
+                let window = bessel_i0(KAISER_BETA * (1.0 - w * w).sqrt()) / norm;
+                cutoff * sinc * window
+            })
+            .collect();
+        Self { half_width, table }
+    }
+
+    fn at(&self, t: f64) -> f64 {
+        let p = t.abs() * TABLE_RESOLUTION as f64;
+        let i = p as usize;
+        if i + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let f = p - i as f64;
+        self.table[i] * (1.0 - f) + self.table[i + 1] * f
+    }
+}
+
+// Band-limited windowed-sinc sample rate conversion. The kernel is tabulated once per call
+// and linearly interpolated between table entries so arbitrary rate ratios work.
+pub fn resample(input: &[f64], from: u32, to: u32) -> Vec<f64> {
+    if from == to || input.is_empty() || from == 0 || to == 0 {
+        return input.to_vec();
+    }
+    let ratio = to as f64 / from as f64;
+    let kernel = Kernel::new(ratio.min(1.0) * PASSBAND);
+    let out_len = (input.len() as f64 * ratio).ceil() as usize;
+
+    (0..out_len)
+        .map(|n| {
+            let center = n as f64 / ratio;
+            let first = (center - kernel.half_width).ceil().max(0.0) as usize;
+            let last = ((center + kernel.half_width).floor() as usize).min(input.len() - 1);
+            (first..=last)
+                .map(|i| input[i] * kernel.at(center - i as f64))
+                .sum()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        oscillator::decode::decode_wav,
+        render::{write_wav, SampleFormat},
+        value::Value,
+        Env,
+    };
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        let input = vec![0.0; 1000];
+        assert_eq!(resample(&input, 44100, 48000).len(), 1089);
+        assert_eq!(resample(&input, 48000, 44100).len(), 919);
+        assert_eq!(resample(&input, 22050, 44100).len(), 2000);
+        assert_eq!(resample(&input, 44100, 44100).len(), 1000);
+    }
+
+    #[test]
+    fn dc_passes_at_unity_gain() {
+        let input = vec![1.0; 4000];
+        for &(from, to) in &[
+            (44100, 48000),
+            (48000, 44100),
+            (22050, 44100),
+            (44100, 8000),
+        ] {
+            let output = resample(&input, from, to);
+            // Away from the edges, where the kernel runs off the end of the input
+            let margin = output.len() / 4;
+            for v in &output[margin..output.len() - margin] {
+                assert!((v - 1.0).abs() < 1e-3, "{} -> {}: {}", from, to, v);
+            }
+        }
+    }
+
+    #[test]
+    fn rendered_sine_keeps_its_frequency() {
+        let mut env = Env::new(32000);
+        let mut value: Value<f64> = crate::oscillator::WaveTableSynth::sin(1000.0).into();
+        let mut out = Cursor::new(vec![]);
+        write_wav(
+            &mut out,
+            &mut value,
+            &mut env,
+            Duration::from_millis(100),
+            SampleFormat::Float32,
+        )
+        .unwrap();
+        let audio = decode_wav(out.get_ref()).unwrap().resampled(48000);
+        assert_eq!(audio.sample_rate, 48000);
+        assert_eq!(audio.original_sample_rate, 32000);
+        assert_eq!(audio.len(), 4800);
+        for (n, v) in audio.channels[0].iter().enumerate().take(3800).skip(1000) {
+            let expected = (2.0 * PI * 1000.0 * n as f64 / 48000.0).sin();
+            assert!((v - expected).abs() < 0.01);
+        }
+    }
+}
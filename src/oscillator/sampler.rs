@@ -1,103 +1,184 @@
+use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::path::Path;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 
-use minimp3::{Decoder, Error, Frame};
 use regex::Regex;
 
 use crate::{
     note::Pitch,
-    value::{Value, ValueNode},
+    oscillator::decode::{decode_file, decode_raw, DecodeError, DecodedAudio, RawFormat},
+    value::{Frame, MultiSample, Value, ValueNode},
     Env,
 };
 
-pub struct SampleSet {
-    samples: Vec<(f64, Vec<f64>)>,
+#[derive(Debug)]
+pub enum SampleSetError {
+    Io { path: PathBuf, error: io::Error },
+    Decode { path: PathBuf, error: DecodeError },
+    UnsupportedFormat { path: PathBuf, message: String },
+    BadFilenamePattern { path: PathBuf, message: String },
+    MissingNamedGroup { path: PathBuf, group: &'static str },
 }
 
-impl SampleSet {
-    pub fn from_directory(path: impl AsRef<Path>, pattern: &Regex) -> Self {
-        let mut samples = vec![];
-        for entry in fs::read_dir(path.as_ref()).unwrap() {
-            let entry = entry.unwrap();
-            if let Some(captures) = pattern.captures(entry.path().to_str().unwrap()) {
-                let note = match captures
-                    .name("note")
-                    .unwrap()
-                    .as_str()
-                    .to_uppercase()
-                    .as_str()
-                {
-                    "C" => 0,
-                    "CS" => 1,
-                    "C#" => 1,
-                    "D" => 2,
-                    "DS" => 3,
-                    "D#" => 3,
-                    "E" => 4,
-                    "F" => 5,
-                    "FS" => 6,
-                    "F#" => 6,
-                    "G" => 7,
-                    "GS" => 8,
-                    "G#" => 8,
-                    "A" => 9,
-                    "AS" => 10,
-                    "A#" => 10,
-                    "B" => 11,
-                    _ => panic!(),
-                };
-                let octave = captures
-                    .name("octave")
-                    .unwrap()
-                    .as_str()
-                    .parse::<u32>()
-                    .unwrap();
-                let adjusted_note = note + (octave + 1) * 12;
-
-                samples.push((
-                    (adjusted_note as f64).frequency_from_midi(),
-                    Self::samples_from_file(entry.path()),
-                ));
+impl SampleSetError {
+    pub(crate) fn from_decode(path: &Path, error: DecodeError) -> Self {
+        let path = path.to_path_buf();
+        match error {
+            DecodeError::Io(error) => SampleSetError::Io { path, error },
+            DecodeError::Unsupported(message) => {
+                SampleSetError::UnsupportedFormat { path, message }
             }
+            error => SampleSetError::Decode { path, error },
         }
-
-        Self { samples }
     }
+}
 
-    fn samples_from_file(path: impl AsRef<Path>) -> Vec<f64> {
-        let mut decoder = Decoder::new(File::open(path).unwrap());
-        let mut sample = vec![];
-        loop {
-            match decoder.next_frame() {
-                Ok(Frame {
-                    data,
-                    sample_rate,
-                    channels,
-                    ..
-                }) => {
-                    if channels != 1 {
-                        panic!()
-                    }
-                    if sample_rate != 44100 {
-                        panic!()
-                    }
-                    sample.extend(data.iter().cloned().map(|s| s as f64 / 65536.0));
-                }
-                Err(Error::Eof) => break,
-                Err(e) => panic!("{:?}", e),
+impl fmt::Display for SampleSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleSetError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            SampleSetError::Decode { path, error } => write!(f, "{}: {}", path.display(), error),
+            SampleSetError::UnsupportedFormat { path, message } => {
+                write!(f, "{}: unsupported format: {}", path.display(), message)
+            }
+            SampleSetError::BadFilenamePattern { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
             }
+            SampleSetError::MissingNamedGroup { path, group } => write!(
+                f,
+                "{}: pattern has no capture group named '{}'",
+                path.display(),
+                group
+            ),
         }
-        sample
     }
+}
 
-    pub fn from_file(path: impl AsRef<Path>, freq: f64) -> Self {
-        Self {
-            samples: vec![(freq, Self::samples_from_file(path))],
+impl Error for SampleSetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SampleSetError::Io { error, .. } => Some(error),
+            SampleSetError::Decode { error, .. } => Some(error),
+            _ => None,
         }
     }
+}
+
+pub struct SampleSet {
+    samples: Vec<(f64, DecodedAudio)>,
+}
+
+impl SampleSet {
+    pub fn from_directory(
+        path: impl AsRef<Path>,
+        pattern: &Regex,
+        env: &Env,
+    ) -> Result<Self, SampleSetError> {
+        let path = path.as_ref();
+        let io_error = |error| SampleSetError::Io {
+            path: path.to_path_buf(),
+            error,
+        };
+        let mut samples = vec![];
+        for entry in fs::read_dir(path).map_err(io_error)? {
+            let entry_path = entry.map_err(io_error)?.path();
+            let captures = match entry_path.to_str().and_then(|p| pattern.captures(p)) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            let group = |group| {
+                captures.name(group).map(|m| m.as_str()).ok_or_else(|| {
+                    SampleSetError::MissingNamedGroup {
+                        path: entry_path.clone(),
+                        group,
+                    }
+                })
+            };
+            let bad_filename = |message| SampleSetError::BadFilenamePattern {
+                path: entry_path.clone(),
+                message,
+            };
+
+            let note_name = group("note")?;
+            let note = match note_name.to_uppercase().as_str() {
+                "C" => 0,
+                "CS" => 1,
+                "C#" => 1,
+                "D" => 2,
+                "DS" => 3,
+                "D#" => 3,
+                "E" => 4,
+                "F" => 5,
+                "FS" => 6,
+                "F#" => 6,
+                "G" => 7,
+                "GS" => 8,
+                "G#" => 8,
+                "A" => 9,
+                "AS" => 10,
+                "A#" => 10,
+                "B" => 11,
+                _ => return Err(bad_filename(format!("unknown note name '{}'", note_name))),
+            };
+            let octave_name = group("octave")?;
+            let octave = octave_name
+                .parse::<i32>()
+                .map_err(|_| bad_filename(format!("bad octave '{}'", octave_name)))?;
+            let adjusted_note = note + (octave + 1) * 12;
+
+            samples.push((
+                (adjusted_note as f64).frequency_from_midi(),
+                Self::samples_from_file(&entry_path, env)?,
+            ));
+        }
+
+        Ok(Self { samples })
+    }
+
+    fn samples_from_file(path: &Path, env: &Env) -> Result<DecodedAudio, SampleSetError> {
+        decode_file(path)
+            .map(|audio| audio.resampled(env.sample_rate))
+            .map_err(|e| SampleSetError::from_decode(path, e))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, freq: f64, env: &Env) -> Result<Self, SampleSetError> {
+        Ok(Self {
+            samples: vec![(freq, Self::samples_from_file(path.as_ref(), env)?)],
+        })
+    }
+
+    pub fn from_raw_file(
+        path: impl AsRef<Path>,
+        freq: f64,
+        format: RawFormat,
+        env: &Env,
+    ) -> Result<Self, SampleSetError> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|error| SampleSetError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let audio = decode_raw(&data, format).map_err(|e| SampleSetError::from_decode(path, e))?;
+        Ok(Self {
+            samples: vec![(freq, audio.resampled(env.sample_rate))],
+        })
+    }
 
     pub fn play<'a>(&'a self, freq: f64) -> Option<Value<'a, f64>> {
+        self.sampler(freq)
+    }
+
+    pub fn play_stereo<'a>(&'a self, freq: f64) -> Option<Value<'a, MultiSample<f64>>> {
+        self.sampler(freq)
+    }
+
+    fn sampler<'a, T: Frame + 'a>(&'a self, freq: f64) -> Option<Value<'a, T>> {
+        if self.samples.is_empty() {
+            return None;
+        }
         let mut idxs: Vec<_> = (0..self.samples.len()).collect();
         idxs.sort_by_key(|i| ((self.samples[*i].0 - freq).abs() * 10000.0) as i32);
 
@@ -113,33 +194,41 @@ impl SampleSet {
     }
 }
 
-struct Sampler<'a> {
-    samples: &'a [f64],
+struct Sampler<'a, T> {
+    samples: &'a DecodedAudio,
     pos: f64,
     rate: f64,
+    _frame: PhantomData<T>,
 }
 
-impl<'a> Sampler<'a> {
-    fn new(samples: &'a [f64], rate: f64) -> Self {
+impl<'a, T> Sampler<'a, T> {
+    fn new(samples: &'a DecodedAudio, rate: f64) -> Self {
         Self {
             samples,
             pos: 0.0,
             rate,
+            _frame: PhantomData,
         }
     }
 }
 
-impl<'a> ValueNode for Sampler<'a> {
-    type T = f64;
-    fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
-            buffer[i] = if self.pos as usize >= self.samples.len() {
-                0.0
+impl<'a, T: Frame> ValueNode for Sampler<'a, T> {
+    type T = T;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        // Still play at the right pitch if the set was loaded for a different sample rate
+        let rate = self.rate * self.samples.sample_rate as f64 / env.sample_rate as f64;
+        for sample in &mut buffer[0..samples] {
+            *sample = if self.pos as usize >= self.samples.len() {
+                T::default()
             } else {
-                let s = self.samples[self.pos as usize];
-                self.pos += self.rate;
+                let s = self.samples.frame(self.pos as usize);
+                self.pos += rate;
                 s
             };
         }
     }
+
+    fn is_finished(&self) -> bool {
+        self.pos as usize >= self.samples.len()
+    }
 }
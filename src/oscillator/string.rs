@@ -8,6 +8,8 @@ use crate::{
 };
 
 pub struct PluckedString {
+    freq: f64,
+    sample_rate: u32,
     buffer: Vec<f64>,
     smoothing: f64,
 
@@ -18,13 +20,11 @@ pub struct PluckedString {
 
 impl PluckedString {
     pub fn new(freq: f64, smoothing: f64) -> Self {
-        let mut rng = rand::thread_rng();
-        let buffer_length = 44100 / freq as usize;
         let amp = 1.0 + ((freq.min(1000.0) - 300.0).max(0.0) / 700.0) * 3.0;
         Self {
-            buffer: (0..buffer_length)
-                .map(|_| *[1.0, -1.0].choose(&mut rng).unwrap())
-                .collect(),
+            freq,
+            sample_rate: 0,
+            buffer: vec![],
             smoothing,
 
             amp,
@@ -36,14 +36,23 @@ impl PluckedString {
 
 impl ValueNode for PluckedString {
     type T = f64;
-    fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        if self.sample_rate != env.sample_rate {
+            self.sample_rate = env.sample_rate;
+            let mut rng = rand::thread_rng();
+            let buffer_length = ((env.sample_rate as f64 / self.freq) as usize).max(1);
+            self.buffer = (0..buffer_length)
+                .map(|_| *[1.0, -1.0].choose(&mut rng).unwrap())
+                .collect();
+            self.position = 0;
+        }
+        for out in &mut buffer[0..samples] {
             let sample = self.smoothing * self.buffer[self.position]
                 + (1.0 - self.smoothing) * self.previous;
             self.buffer[self.position] = sample;
             self.previous = sample;
             self.position = (self.position + 1) % self.buffer.len();
-            buffer[i] = sample * self.amp;
+            *out = sample * self.amp;
         }
     }
 }
@@ -72,8 +81,14 @@ impl<'a> DrivenString<'a> {
 impl<'a> ValueNode for DrivenString<'a> {
     type T = f64;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut output: Vec<f64> = Vec::with_capacity(samples);
-        self.output.fill_buffer(env, &mut output, samples);
-        buffer[0..samples].copy_from_slice(&output);
+        self.output.fill_buffer(env, buffer, samples);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        self.output.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.output.is_finished()
     }
 }
@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use rand::Rng;
+
+use crate::{
+    value::{scratch, MultiSample, Value, ValueNode},
+    Env,
+};
+
+// Hands each voice the frequency Unison worked out for it this block
+struct VoiceFrequency(Rc<RefCell<Vec<f64>>>);
+
+impl ValueNode for VoiceFrequency {
+    type T = f64;
+    fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+        buffer[0..samples].copy_from_slice(&self.0.borrow()[0..samples]);
+    }
+}
+
+struct Voice<'a> {
+    oscillator: Value<'a, f64>,
+    frequency: Rc<RefCell<Vec<f64>>>,
+    // Where the voice sits in the stack, from -1 to 1
+    offset: f64,
+    centre: bool,
+}
+
+// Stacks detuned copies of an oscillator, supersaw style. `oscillator` is called once per
+// voice with that voice's frequency and a random starting phase from 0 to 1. Voices are
+// spread evenly across the detune range and across the stereo field.
+pub struct Unison<'a> {
+    frequency: Value<'a, f64>,
+    detune: Value<'a, f64>,
+    mix: Value<'a, f64>,
+    stereo_spread: f64,
+    voices: Vec<Voice<'a>>,
+
+    frequency_buffer: Vec<f64>,
+    detune_buffer: Vec<f64>,
+    mix_buffer: Vec<f64>,
+    voice_buffer: Vec<f64>,
+}
+
+impl<'a> Unison<'a> {
+    pub fn new(
+        voices: usize,
+        frequency: impl Into<Value<'a, f64>>,
+        mut oscillator: impl FnMut(Value<'a, f64>, f64) -> Value<'a, f64>,
+    ) -> Self {
+        let count = voices.max(1);
+        let mut rng = rand::thread_rng();
+        let voices: Vec<_> = (0..count)
+            .map(|i| {
+                let offset = if count == 1 {
+                    0.0
+                } else {
+                    i as f64 / (count - 1) as f64 * 2.0 - 1.0
+                };
+                let frequency = Rc::new(RefCell::new(vec![]));
+                Voice {
+                    oscillator: oscillator(
+                        VoiceFrequency(frequency.clone()).into(),
+                        rng.gen::<f64>(),
+                    ),
+                    frequency,
+                    offset,
+                    centre: false,
+                }
+            })
+            .collect();
+        let closest = voices
+            .iter()
+            .map(|v| v.offset.abs())
+            .fold(f64::INFINITY, f64::min);
+        let voices = voices
+            .into_iter()
+            .map(|mut v| {
+                v.centre = v.offset.abs() - closest < 1e-9;
+                v
+            })
+            .collect();
+        Self {
+            frequency: frequency.into(),
+            detune: 20.0.into(),
+            mix: 1.0.into(),
+            stereo_spread: 1.0,
+            voices,
+
+            frequency_buffer: vec![],
+            detune_buffer: vec![],
+            mix_buffer: vec![],
+            voice_buffer: vec![],
+        }
+    }
+
+    // How far the outermost voices are detuned, in cents either side of the frequency
+    pub fn detune(mut self, detune: impl Into<Value<'a, f64>>) -> Self {
+        self.detune = detune.into();
+        self
+    }
+
+    // Level of the outer voices relative to the centre ones, from 0 for only the centre to
+    // 1 for all voices equal
+    pub fn mix(mut self, mix: impl Into<Value<'a, f64>>) -> Self {
+        self.mix = mix.into();
+        self
+    }
+
+    // From 0 for every voice in the middle to 1 for the outermost ones hard left and right
+    pub fn stereo_spread(mut self, stereo_spread: f64) -> Self {
+        self.stereo_spread = stereo_spread.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl<'a> ValueNode for Unison<'a> {
+    type T = MultiSample<f64>;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let frequency = scratch(&mut self.frequency_buffer, samples);
+        self.frequency.fill_buffer(env, frequency, samples);
+        let detune = scratch(&mut self.detune_buffer, samples);
+        self.detune.fill_buffer(env, detune, samples);
+        let mix = scratch(&mut self.mix_buffer, samples);
+        self.mix.fill_buffer(env, mix, samples);
+
+        for b in &mut buffer[0..samples] {
+            *b = MultiSample(0.0, 0.0);
+        }
+        let outer = self.voices.iter().filter(|v| !v.centre).count() as f64;
+        let centre = self.voices.len() as f64 - outer;
+        for voice in &mut self.voices {
+            {
+                let mut voice_frequency = voice.frequency.borrow_mut();
+                let voice_frequency = scratch(&mut voice_frequency, samples);
+                for i in 0..samples {
+                    let cents = detune[i] * voice.offset;
+                    voice_frequency[i] = frequency[i] * 2.0f64.powf(cents / 1200.0);
+                }
+            }
+            let output = scratch(&mut self.voice_buffer, samples);
+            voice.oscillator.fill_buffer(env, output, samples);
+
+            // Equal power pan
+            let angle = (voice.offset * self.stereo_spread + 1.0) * PI / 4.0;
+            let (left, right) = (angle.cos(), angle.sin());
+            for i in 0..samples {
+                let mix = mix[i].clamp(0.0, 1.0);
+                // Voices are uncorrelated so their powers add
+                let level = if voice.centre { 1.0 } else { mix };
+                let v = output[i] * level / (centre + mix * mix * outer).sqrt();
+                buffer[i].0 += v * left;
+                buffer[i].1 += v * right;
+            }
+        }
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.frequency_buffer, max_block_size);
+        scratch(&mut self.detune_buffer, max_block_size);
+        scratch(&mut self.mix_buffer, max_block_size);
+        scratch(&mut self.voice_buffer, max_block_size);
+        self.frequency.prepare(max_block_size);
+        self.detune.prepare(max_block_size);
+        self.mix.prepare(max_block_size);
+        for voice in &mut self.voices {
+            scratch(&mut voice.frequency.borrow_mut(), max_block_size);
+            voice.oscillator.prepare(max_block_size);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.voices.iter().all(|v| v.oscillator.is_finished())
+    }
+}
@@ -0,0 +1,160 @@
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+use num::complex::Complex64;
+
+use crate::{
+    oscillator::{decode::decode_file, sampler::SampleSetError},
+    spectral::RealFft,
+};
+
+// The most harmonics any band keeps, which covers everything audible down to a couple of Hz
+pub const MAX_HARMONICS: usize = 8192;
+
+// One or more single cycle frames, each stored as a set of band-limited tables. There is
+// one band per octave of harmonic count, from a lone fundamental up to everything the source
+// had, and every band holds a table for every frame. Cloning is cheap so one WaveTable can
+// feed any number of oscillators.
+#[derive(Clone, Debug)]
+pub struct WaveTable {
+    // Paired with the number of their highest harmonic and ordered from most to fewest
+    bands: Arc<Vec<(f64, Vec<Vec<f64>>)>>,
+    frames: usize,
+}
+
+impl WaveTable {
+    // Splits `samples` into frames of `frame_size` samples, each one cycle of the wave. Any
+    // incomplete frame at the end is dropped.
+    pub fn from_samples(samples: &[f64], frame_size: usize) -> Self {
+        let frame_size = frame_size.max(2);
+        let spectra: Vec<_> = samples
+            .chunks_exact(frame_size)
+            .map(harmonics_of)
+            .collect();
+        Self::from_spectra(&spectra, frame_size / 2 - 1)
+    }
+
+    // A single frame made from sines, where `amplitudes[0]` is the fundamental
+    pub fn from_partials(amplitudes: &[f64]) -> Self {
+        Self::from_partial_frames(&[amplitudes.to_vec()])
+    }
+
+    // One frame for each list of partials, as in `from_partials`
+    pub fn from_partial_frames(frames: &[Vec<f64>]) -> Self {
+        let harmonics = frames.iter().map(|f| f.len()).max().unwrap_or(0);
+        let spectra: Vec<_> = frames
+            .iter()
+            .map(|amplitudes| {
+                std::iter::once(Complex64::new(0.0, 0.0))
+                    .chain(amplitudes.iter().map(|a| Complex64::new(0.0, -a)))
+                    .collect()
+            })
+            .collect();
+        Self::from_spectra(&spectra, harmonics)
+    }
+
+    // Loads a file of concatenated frames, mixing all of its channels together
+    pub fn from_wav_file(
+        path: impl AsRef<Path>,
+        frame_size: usize,
+    ) -> Result<Self, SampleSetError> {
+        let path = path.as_ref();
+        let audio = decode_file(path).map_err(|e| SampleSetError::from_decode(path, e))?;
+        let channels = audio.channels.len().max(1) as f64;
+        let samples: Vec<f64> = (0..audio.len())
+            .map(|i| audio.channels.iter().map(|c| c[i]).sum::<f64>() / channels)
+            .collect();
+        Ok(Self::from_samples(&samples, frame_size))
+    }
+
+    // Builds the table set from a series of sines where harmonic `n` has `amplitude(n)`
+    pub(crate) fn from_harmonic_series(amplitude: impl Fn(usize) -> f64) -> Self {
+        let spectrum: Vec<_> = (0..=MAX_HARMONICS)
+            .map(|n| {
+                if n == 0 {
+                    Complex64::new(0.0, 0.0)
+                } else {
+                    Complex64::new(0.0, -amplitude(n))
+                }
+            })
+            .collect();
+        Self::from_spectra(&[spectrum], MAX_HARMONICS)
+    }
+
+    // `spectra` holds, for each frame, the complex amplitude of every harmonic with the DC
+    // term first. DC is always left out of the tables.
+    fn from_spectra(spectra: &[Vec<Complex64>], max_harmonics: usize) -> Self {
+        let max_harmonics = max_harmonics.clamp(1, MAX_HARMONICS);
+        let mut counts = vec![];
+        let mut harmonics = 1;
+        while harmonics < max_harmonics {
+            counts.push(harmonics);
+            harmonics *= 2;
+        }
+        counts.push(max_harmonics);
+
+        let bands = counts
+            .into_iter()
+            .rev()
+            .map(|harmonics| {
+                let len = (harmonics * 4).next_power_of_two().max(512);
+                let mut fft = RealFft::new(len);
+                let mut spectrum = vec![Complex64::new(0.0, 0.0); len / 2 + 1];
+                let frames = spectra
+                    .iter()
+                    .map(|source| {
+                        for (n, bin) in spectrum.iter_mut().enumerate() {
+                            *bin = if n == 0 || n > harmonics {
+                                Complex64::new(0.0, 0.0)
+                            } else {
+                                source.get(n).cloned().unwrap_or_default() * (len as f64 / 2.0)
+                            };
+                        }
+                        let mut table = vec![0.0; len];
+                        fft.inverse(&spectrum, &mut table);
+                        table
+                    })
+                    .collect();
+                (harmonics as f64, frames)
+            })
+            .collect();
+        Self {
+            bands: Arc::new(bands),
+            frames: spectra.len(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub(crate) fn bands(&self) -> &[(f64, Vec<Vec<f64>>)] {
+        &self.bands
+    }
+}
+
+// Complex amplitude of each harmonic of one cycle, DC first. Uses the FFT when the length
+// allows and a plain DFT otherwise.
+fn harmonics_of(frame: &[f64]) -> Vec<Complex64> {
+    let len = frame.len();
+    let scale = 2.0 / len as f64;
+    if len.is_power_of_two() {
+        let mut spectrum = vec![Complex64::new(0.0, 0.0); len / 2 + 1];
+        RealFft::new(len).forward(frame, &mut spectrum);
+        spectrum.into_iter().map(|bin| bin * scale).collect()
+    } else {
+        (0..=len / 2)
+            .map(|n| {
+                frame
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        Complex64::from_polar(v, &(-2.0 * PI * (n * i) as f64 / len as f64))
+                    })
+                    .sum::<Complex64>()
+                    * scale
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,498 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{
+    render::render_chunks,
+    spectral::{magnitudes, to_db, Stft, Window},
+    value::{Frame, Value},
+    Env,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+// An RGB image, rows top to bottom
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0, 0, 0]; width * height],
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = color;
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, format: ImageFormat) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            ImageFormat::Png => self.write_png(&mut out)?,
+            ImageFormat::Ppm => self.write_ppm(&mut out)?,
+        }
+        out.flush()
+    }
+
+    // Binary (P6) PPM
+    pub fn write_ppm(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let bytes: Vec<u8> = self.pixels.iter().flat_map(|p| p.iter().cloned()).collect();
+        out.write_all(&bytes)
+    }
+
+    // Uncompressed PNG. The image data goes into a zlib stream made of stored deflate blocks,
+    // which keeps this free of a compression dependency at the cost of file size.
+    pub fn write_png(&self, mut out: impl Write) -> io::Result<()> {
+        out.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'])?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bit RGB, default compression and filtering, no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_png_chunk(&mut out, b"IHDR", &header)?;
+
+        let mut raw = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for row in self.pixels.chunks(self.width.max(1)) {
+            raw.push(0);
+            for p in row {
+                raw.extend_from_slice(p);
+            }
+        }
+        let mut data = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            data.push(if blocks.peek().is_none() { 1 } else { 0 });
+            let len = block.len() as u16;
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&(!len).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+        data.extend_from_slice(&adler32(&raw).to_be_bytes());
+        write_png_chunk(&mut out, b"IDAT", &data)?;
+        write_png_chunk(&mut out, b"IEND", &[])
+    }
+}
+
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::default();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        let mut table = [0; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        Self {
+            table,
+            value: 0xffff_ffff,
+        }
+    }
+}
+
+impl Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.value = self.table[((self.value ^ *b as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.value ^ 0xffff_ffff
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for v in chunk {
+            a += *v as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// Black through blue, red and yellow to white as `level` goes from 0 to 1
+fn heat(level: f64) -> [u8; 3] {
+    let stops = [
+        [0.0, 0.0, 0.0],
+        [0.1, 0.0, 0.5],
+        [0.8, 0.0, 0.3],
+        [1.0, 0.6, 0.0],
+        [1.0, 1.0, 1.0],
+    ];
+    let position = level.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+    let i = (position as usize).min(stops.len() - 2);
+    let t = position - i as f64;
+    let mut color = [0; 3];
+    for c in 0..3 {
+        let v = stops[i][c] + (stops[i + 1][c] - stops[i][c]) * t;
+        color[c] = (v * 255.0).round() as u8;
+    }
+    color
+}
+
+// Settings for drawing a spectrogram: one column per STFT frame, one row per bin with low
+// frequencies at the bottom, and levels between `min_db` and `max_db` mapped onto a heat
+// scale.
+#[derive(Clone, Debug)]
+pub struct Spectrogram {
+    fft_size: usize,
+    hop: usize,
+    window: Window,
+    min_db: f64,
+    max_db: f64,
+}
+
+impl Default for Spectrogram {
+    fn default() -> Self {
+        Self {
+            fft_size: 1024,
+            hop: 256,
+            window: Window::Hann,
+            min_db: -100.0,
+            max_db: 0.0,
+        }
+    }
+}
+
+impl Spectrogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Rounded up to a power of two. Sets the height of the image to fft_size / 2 + 1.
+    pub fn fft_size(mut self, fft_size: usize) -> Self {
+        self.fft_size = fft_size.max(2).next_power_of_two();
+        self
+    }
+
+    pub fn hop(mut self, hop: usize) -> Self {
+        self.hop = hop.max(1);
+        self
+    }
+
+    pub fn window(mut self, window: Window) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn db_range(mut self, min_db: f64, max_db: f64) -> Self {
+        self.min_db = min_db.min(max_db);
+        self.max_db = max_db.max(min_db);
+        self
+    }
+
+    pub fn image(&self, samples: &[f64]) -> Image {
+        let mut stft = Stft::new(self.fft_size, self.hop.min(self.fft_size), self.window);
+        // Scale so a full scale sine peaks at 0dB whatever the window
+        let gain = 2.0
+            / self
+                .window
+                .coefficients(self.fft_size)
+                .iter()
+                .sum::<f64>()
+                .max(1e-9);
+        let frames = stft.analyze(samples);
+        let bins = stft.bins();
+        let mut image = Image::new(frames.len(), bins);
+        let range = (self.max_db - self.min_db).max(1e-9);
+        for (x, frame) in frames.iter().enumerate() {
+            for (bin, magnitude) in magnitudes(frame).into_iter().enumerate() {
+                let level = (to_db(magnitude * gain) - self.min_db) / range;
+                image.set(x, bins - 1 - bin, heat(level));
+            }
+        }
+        image
+    }
+}
+
+// Settings for drawing a waveform. Each channel gets its own lane, and every column shows
+// the range of the samples that fall in it.
+#[derive(Clone, Debug)]
+pub struct Waveform {
+    width: usize,
+    height: usize,
+}
+
+impl Default for Waveform {
+    fn default() -> Self {
+        Self {
+            width: 1200,
+            height: 300,
+        }
+    }
+}
+
+impl Waveform {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn size(mut self, width: usize, height: usize) -> Self {
+        self.width = width.max(1);
+        self.height = height.max(1);
+        self
+    }
+
+    pub fn image(&self, channels: &[Vec<f64>]) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        let lanes = channels.len().max(1);
+        let lane_height = (self.height / lanes).max(1);
+        for (lane, samples) in channels.iter().enumerate() {
+            let top = lane * lane_height;
+            let centre = top + lane_height / 2;
+            let to_y = |v: f64| {
+                let y = centre as f64 - v.clamp(-1.0, 1.0) * (lane_height as f64 / 2.0 - 1.0);
+                (y.round() as usize).clamp(top, top + lane_height - 1)
+            };
+            for x in 0..self.width {
+                image.set(x, centre, [60, 60, 60]);
+                let start = x * samples.len() / self.width;
+                let end = ((x + 1) * samples.len() / self.width).max(start + 1);
+                let column = &samples[start.min(samples.len())..end.min(samples.len())];
+                if column.is_empty() {
+                    continue;
+                }
+                let low = column.iter().cloned().fold(f64::INFINITY, f64::min);
+                let high = column.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let clipped = low < -1.0 || high > 1.0;
+                let color = if clipped {
+                    [255, 60, 40]
+                } else {
+                    [90, 200, 120]
+                };
+                for y in to_y(high)..=to_y(low) {
+                    image.set(x, y, color);
+                }
+            }
+        }
+        image
+    }
+}
+
+// Renders `duration` worth of `value`, returning the samples of each channel
+pub fn render_channels<'a, T: Frame>(
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    duration: Duration,
+) -> Vec<Vec<f64>> {
+    let total_frames = (duration.as_secs_f64() * env.sample_rate as f64) as usize;
+    let mut channels = vec![Vec::with_capacity(total_frames); T::CHANNELS];
+    let result = render_chunks(value, env, total_frames, false, |chunk| {
+        for frame in chunk {
+            for (c, channel) in channels.iter_mut().enumerate() {
+                channel.push(frame.channel(c));
+            }
+        }
+        Ok(())
+    });
+    // Nothing in the closure can fail
+    result.unwrap();
+    channels
+}
+
+// Renders `duration` worth of `value` and writes its spectrogram. Multichannel signals are
+// mixed down first.
+pub fn render_spectrogram<'a, T: Frame>(
+    path: impl AsRef<Path>,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    duration: Duration,
+    spectrogram: &Spectrogram,
+    format: ImageFormat,
+) -> io::Result<()> {
+    let channels = render_channels(value, env, duration);
+    spectrogram.image(&mix_down(&channels)).save(path, format)
+}
+
+pub fn render_waveform<'a, T: Frame>(
+    path: impl AsRef<Path>,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    duration: Duration,
+    waveform: &Waveform,
+    format: ImageFormat,
+) -> io::Result<()> {
+    let channels = render_channels(value, env, duration);
+    waveform.image(&channels).save(path, format)
+}
+
+pub fn mix_down(channels: &[Vec<f64>]) -> Vec<f64> {
+    let len = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    (0..len)
+        .map(|i| {
+            channels
+                .iter()
+                .map(|c| c.get(i).cloned().unwrap_or(0.0))
+                .sum::<f64>()
+                / channels.len() as f64
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waveform_with_more_channels_than_rows() {
+        let channels = vec![vec![0.5, -0.5, 1.5], vec![0.0; 3], vec![-1.0; 3]];
+        let image = Waveform::new().size(100, 1).image(&channels);
+        assert_eq!((image.width, image.height), (100, 1));
+        assert_eq!(image.pixels.len(), 100);
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        let mut crc = Crc32::default();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+        // Long enough for the sums to wrap
+        let long = vec![0xff; 100_000];
+        let (a, b) = (0..long.len()).fold((1u64, 0u64), |(a, b), _| {
+            let a = (a + 0xff) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(adler32(&long), ((b << 16) | a) as u32);
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn png_with_several_stored_blocks() {
+        // 120 rows of 1 + 200 * 3 bytes is more than one stored block can hold
+        let mut image = Image::new(200, 120);
+        for y in 0..120 {
+            for x in 0..200 {
+                image.set(x, y, [x as u8, y as u8, (x * y) as u8]);
+            }
+        }
+        let mut png = vec![];
+        image.write_png(&mut png).unwrap();
+        assert_eq!(
+            png[0..8],
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']
+        );
+
+        let mut chunks = vec![];
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = be_u32(rest) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let mut crc = Crc32::default();
+            crc.update(kind);
+            crc.update(data);
+            assert_eq!(be_u32(&rest[8 + len..]), crc.finish());
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(be_u32(&chunks[0].1[0..]), 200);
+        assert_eq!(be_u32(&chunks[0].1[4..]), 120);
+
+        let zlib = &chunks[1].1;
+        assert_eq!(zlib[0..2], [0x78, 0x01]);
+        let mut raw = vec![];
+        let mut position = 2;
+        let mut blocks = 0;
+        loop {
+            let last = zlib[position] == 1;
+            let len = u16::from_le_bytes([zlib[position + 1], zlib[position + 2]]);
+            let nlen = u16::from_le_bytes([zlib[position + 3], zlib[position + 4]]);
+            assert_eq!(nlen, !len);
+            raw.extend_from_slice(&zlib[position + 5..position + 5 + len as usize]);
+            position += 5 + len as usize;
+            blocks += 1;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(blocks, 2);
+        assert_eq!(be_u32(&zlib[position..]), adler32(&raw));
+        assert_eq!(position + 4, zlib.len());
+
+        let mut expected = vec![];
+        for row in image.pixels.chunks(200) {
+            expected.push(0);
+            for p in row {
+                expected.extend_from_slice(p);
+            }
+        }
+        assert_eq!(raw, expected);
+    }
+
+    #[test]
+    fn spectrogram_of_a_full_scale_sine() {
+        // Exactly on bin 64 of a 1024 point transform
+        let samples: Vec<_> = (0..8192)
+            .map(|n| (2.0 * std::f64::consts::PI * 64.0 * n as f64 / 1024.0).sin())
+            .collect();
+        let image = Spectrogram::new()
+            .fft_size(1024)
+            .hop(256)
+            .db_range(-100.0, 0.0)
+            .image(&samples);
+        assert_eq!(image.height, 513);
+        let x = image.width / 2;
+        let column: Vec<_> = (0..image.height)
+            .map(|y| image.pixels[y * image.width + x])
+            .collect();
+        let brightest = (0..column.len())
+            .max_by_key(|y| column[*y].iter().map(|c| *c as u32).sum::<u32>())
+            .unwrap();
+        assert_eq!(brightest, 512 - 64);
+        // 0dB is the top of the heat scale
+        assert!(
+            column[brightest].iter().all(|c| *c >= 250),
+            "{:?}",
+            column[brightest]
+        );
+        assert_eq!(column[100], [0, 0, 0]);
+    }
+}
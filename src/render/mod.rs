@@ -0,0 +1,314 @@
+mod image;
+pub use image::*;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{
+    value::{Frame, Value},
+    Env,
+};
+
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl SampleFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::Pcm16 => 2,
+            SampleFormat::Pcm24 => 3,
+            SampleFormat::Float32 => 4,
+        }
+    }
+
+    fn write_sample(self, out: &mut Vec<u8>, sample: f64) {
+        match self {
+            SampleFormat::Pcm16 => {
+                let v = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            SampleFormat::Pcm24 => {
+                let v = (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+                out.extend_from_slice(&v.to_le_bytes()[0..3]);
+            }
+            SampleFormat::Float32 => {
+                out.extend_from_slice(&(sample as f32).to_le_bytes());
+            }
+        }
+    }
+}
+
+pub fn render_wav<'a, T: Frame>(
+    path: impl AsRef<Path>,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    duration: Duration,
+    format: SampleFormat,
+) -> io::Result<()> {
+    let out = BufWriter::new(File::create(path)?);
+    write_wav(out, value, env, duration, format)
+}
+
+// Streams `duration` worth of `value` to `out` as a RIFF/WAVE file. Rendering happens
+// CHUNK_SIZE frames at a time and `env.time` is advanced after each chunk.
+pub fn write_wav<'a, T: Frame>(
+    mut out: impl Write,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    duration: Duration,
+    format: SampleFormat,
+) -> io::Result<()> {
+    let total_frames = (duration.as_secs_f64() * env.sample_rate as f64) as usize;
+    out.write_all(&wav_header::<T>(env.sample_rate, total_frames, format)?)?;
+    let rendered = render_frames(&mut out, value, env, total_frames, format, false)?;
+    if !(rendered * T::CHANNELS * format.bytes_per_sample()).is_multiple_of(2) {
+        out.write_all(&[0])?;
+    }
+    out.flush()
+}
+
+pub fn render_wav_until_finished<'a, T: Frame>(
+    path: impl AsRef<Path>,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    max_duration: Duration,
+    format: SampleFormat,
+) -> io::Result<()> {
+    let out = BufWriter::new(File::create(path)?);
+    write_wav_until_finished(out, value, env, max_duration, format)
+}
+
+// Like write_wav, but stops as soon as `value` reports that it has finished, or after
+// `max_duration` if it never does. The header is rewritten once the length is known, so only
+// what was actually rendered has to fit in a WAV file.
+pub fn write_wav_until_finished<'a, T: Frame>(
+    mut out: impl Write + Seek,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    max_duration: Duration,
+    format: SampleFormat,
+) -> io::Result<()> {
+    let max_frames = (max_duration.as_secs_f64() * env.sample_rate as f64) as usize;
+    let header_start = out.stream_position()?;
+    out.write_all(&wav_header::<T>(env.sample_rate, 0, format)?)?;
+    let rendered = render_frames(&mut out, value, env, max_frames, format, true)?;
+    if !(rendered * T::CHANNELS * format.bytes_per_sample()).is_multiple_of(2) {
+        out.write_all(&[0])?;
+    }
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(header_start))?;
+    out.write_all(&wav_header::<T>(env.sample_rate, rendered, format)?)?;
+    out.seek(SeekFrom::Start(end))?;
+    out.flush()
+}
+
+fn wav_header<T: Frame>(
+    sample_rate: u32,
+    total_frames: usize,
+    format: SampleFormat,
+) -> io::Result<Vec<u8>> {
+    let block_align = T::CHANNELS * format.bytes_per_sample();
+    let data_len = total_frames * block_align;
+    let padding = data_len % 2;
+
+    let fmt_len = if format == SampleFormat::Float32 {
+        18
+    } else {
+        16
+    };
+    let fact_len = if format == SampleFormat::Float32 {
+        12
+    } else {
+        0
+    };
+    let riff_len = 4 + (8 + fmt_len) + fact_len + (8 + data_len + padding);
+    if riff_len > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "render is too long for a WAV file",
+        ));
+    }
+
+    let format_tag: u16 = if format == SampleFormat::Float32 {
+        3
+    } else {
+        1
+    };
+    let mut header = Vec::with_capacity(64);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(riff_len as u32).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&(fmt_len as u32).to_le_bytes());
+    header.extend_from_slice(&format_tag.to_le_bytes());
+    header.extend_from_slice(&(T::CHANNELS as u16).to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&((sample_rate as usize * block_align) as u32).to_le_bytes());
+    header.extend_from_slice(&(block_align as u16).to_le_bytes());
+    header.extend_from_slice(&((format.bytes_per_sample() * 8) as u16).to_le_bytes());
+    if format == SampleFormat::Float32 {
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(b"fact");
+        header.extend_from_slice(&4u32.to_le_bytes());
+        header.extend_from_slice(&(total_frames as u32).to_le_bytes());
+    }
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&(data_len as u32).to_le_bytes());
+    Ok(header)
+}
+
+// Renders up to `total_frames` frames to `out`, returning how many were written
+fn render_frames<'a, T: Frame>(
+    out: &mut impl Write,
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    total_frames: usize,
+    format: SampleFormat,
+    stop_when_finished: bool,
+) -> io::Result<usize> {
+    let mut bytes = Vec::with_capacity(CHUNK_SIZE * T::CHANNELS * format.bytes_per_sample());
+    render_chunks(value, env, total_frames, stop_when_finished, |chunk| {
+        bytes.clear();
+        for frame in chunk {
+            for c in 0..T::CHANNELS {
+                format.write_sample(&mut bytes, frame.channel(c));
+            }
+        }
+        out.write_all(&bytes)
+    })
+}
+
+// Renders up to `total_frames` frames CHUNK_SIZE at a time, handing each chunk to `f`
+fn render_chunks<'a, T: Frame>(
+    value: &mut Value<'a, T>,
+    env: &mut Env,
+    total_frames: usize,
+    stop_when_finished: bool,
+    mut f: impl FnMut(&[T]) -> io::Result<()>,
+) -> io::Result<usize> {
+    value.prepare(CHUNK_SIZE);
+    let start = env.time;
+    let mut buffer = vec![T::default(); CHUNK_SIZE];
+    let mut rendered = 0;
+    while rendered < total_frames && !(stop_when_finished && value.is_finished()) {
+        let samples = (total_frames - rendered).min(CHUNK_SIZE);
+        value.fill_buffer(env, &mut buffer, samples);
+        f(&buffer[0..samples])?;
+        rendered += samples;
+        env.time = start + Duration::from_secs_f64(rendered as f64 / env.sample_rate as f64);
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::decode::decode_wav;
+    use crate::value::MultiSample;
+    use std::io::Cursor;
+
+    struct Ramp(f64);
+
+    impl crate::value::ValueNode for Ramp {
+        type T = MultiSample<f64>;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = MultiSample(self.0, -self.0);
+                self.0 += 0.001;
+            }
+        }
+    }
+
+    fn round_trip(format: SampleFormat, tolerance: f64) {
+        let mut env = Env::new(22050);
+        let mut value: Value<MultiSample<f64>> = Ramp(-0.5).into();
+        let mut out = Cursor::new(vec![]);
+        write_wav(
+            &mut out,
+            &mut value,
+            &mut env,
+            Duration::from_millis(50),
+            format,
+        )
+        .unwrap();
+
+        let decoded = decode_wav(out.get_ref()).unwrap();
+        assert_eq!(decoded.sample_rate, 22050);
+        assert_eq!(decoded.channels.len(), 2);
+        assert_eq!(decoded.len(), 1102);
+        for i in 0..decoded.len() {
+            let expected = -0.5 + i as f64 * 0.001;
+            assert!((decoded.channels[0][i] - expected).abs() < tolerance);
+            assert!((decoded.channels[1][i] + expected).abs() < tolerance);
+        }
+    }
+
+    // The writer scales by 2^(bits-1) - 1 and the decoder by 2^(bits-1), so allow two steps
+    #[test]
+    fn wav_round_trip_pcm16() {
+        round_trip(SampleFormat::Pcm16, 2.0 / 32767.0);
+    }
+
+    #[test]
+    fn wav_round_trip_pcm24() {
+        round_trip(SampleFormat::Pcm24, 2.0 / 8_388_607.0);
+    }
+
+    #[test]
+    fn wav_round_trip_float32() {
+        round_trip(SampleFormat::Float32, 1e-6);
+    }
+
+    // Plays a constant for `remaining` samples and then reports being finished
+    struct Burst(usize);
+
+    impl crate::value::ValueNode for Burst {
+        type T = MultiSample<f64>;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = MultiSample(0.25, -0.25);
+                self.0 = self.0.saturating_sub(1);
+            }
+        }
+
+        fn is_finished(&self) -> bool {
+            self.0 == 0
+        }
+    }
+
+    #[test]
+    fn wav_until_finished_rewrites_the_header() {
+        let mut env = Env::new(44100);
+        let mut value: Value<MultiSample<f64>> = Burst(3000).into();
+        let mut out = Cursor::new(vec![]);
+        // Four hours of stereo float is too long for a WAV file, but only what is rendered counts
+        write_wav_until_finished(
+            &mut out,
+            &mut value,
+            &mut env,
+            Duration::from_secs(4 * 3600),
+            SampleFormat::Float32,
+        )
+        .unwrap();
+
+        // Finishing is only checked between chunks
+        let frames = 3 * CHUNK_SIZE;
+        let bytes = out.get_ref();
+        let data_len = frames as u32 * 8;
+        assert_eq!(bytes.len(), 58 + data_len as usize);
+        assert_eq!(bytes[4..8], (50 + data_len).to_le_bytes());
+        assert_eq!(bytes[46..50], (frames as u32).to_le_bytes());
+        assert_eq!(bytes[54..58], data_len.to_le_bytes());
+        let decoded = decode_wav(bytes).unwrap();
+        assert_eq!(decoded.len(), frames);
+        assert!(decoded.channels[0].iter().all(|v| *v == 0.25));
+    }
+}
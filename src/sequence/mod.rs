@@ -1,8 +1,7 @@
 use crate::{
-    value::{Value, ValueNode},
+    value::{scratch, Frame, Value, ValueNode, SILENCE},
     Env,
 };
-use std::collections::VecDeque;
 use std::ops::Add;
 use std::time::Duration;
 
@@ -12,12 +11,72 @@ pub struct Note {
     pub frequency: f64,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoiceStealing {
+    Oldest,
+    Quietest,
+    // The voice with the lowest pitch, judged by how often its output crosses zero
+    Lowest,
+}
+
+struct Voice<'a, T> {
+    value: Value<'a, T>,
+    started: u64,
+    ended: bool,
+    silent_samples: usize,
+    level: f64,
+    crossing_rate: f64,
+    previous: f64,
+}
+
+impl<'a, T: Frame> Voice<'a, T> {
+    fn new(value: Value<'a, T>, started: u64) -> Self {
+        Self {
+            value,
+            started,
+            ended: false,
+            silent_samples: 0,
+            level: 0.0,
+            crossing_rate: 0.0,
+            previous: 0.0,
+        }
+    }
+
+    fn measure(&mut self, output: &[T]) {
+        let mut peak: f64 = 0.0;
+        let mut crossings = 0;
+        for frame in output {
+            for c in 0..T::CHANNELS {
+                peak = peak.max(frame.channel(c).abs());
+            }
+            let v = frame.channel(0);
+            if (v >= 0.0) != (self.previous >= 0.0) {
+                crossings += 1;
+            }
+            self.previous = v;
+        }
+        self.level = peak;
+        self.crossing_rate = crossings as f64 / output.len().max(1) as f64;
+        if peak < SILENCE {
+            self.silent_samples += output.len();
+        } else {
+            self.silent_samples = 0;
+        }
+    }
+}
+
 pub struct FancySequence<'a, S, T> {
     state: S,
     generator: Box<dyn Fn(&mut S) -> Option<(Duration, Value<'a, T>)> + 'a>,
 
-    current_notes: VecDeque<Option<Value<'a, T>>>,
+    voices: Vec<Voice<'a, T>>,
+    voice_limit: Option<(usize, VoiceStealing)>,
+    silence_timeout: Option<Duration>,
+    notes_started: u64,
+    exhausted: bool,
     samples_remaining: usize,
+    result: Vec<T>,
+    max_block_size: usize,
 }
 
 impl<'a, S, T> FancySequence<'a, S, T> {
@@ -29,8 +88,49 @@ impl<'a, S, T> FancySequence<'a, S, T> {
             state: initial_state,
             generator: Box::new(generator),
 
-            current_notes: (0..10).map(|_| None).collect(),
+            voices: vec![],
+            voice_limit: None,
+            silence_timeout: Some(Duration::from_secs(1)),
+            notes_started: 0,
+            exhausted: false,
             samples_remaining: 0,
+            result: vec![],
+            max_block_size: 0,
+        }
+    }
+
+    pub fn voice_limit(mut self, limit: usize, stealing: VoiceStealing) -> Self {
+        self.voice_limit = Some((limit.max(1), stealing));
+        self
+    }
+
+    // Also drops a voice once its note has ended and it has stayed silent for `timeout`, so
+    // notes that never report being finished don't pile up. Defaults to a second; None keeps
+    // them until they finish.
+    pub fn silence_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.silence_timeout = timeout;
+        self
+    }
+
+    fn steal_voice(&mut self) {
+        if let Some((limit, stealing)) = self.voice_limit {
+            while self.voices.len() >= limit {
+                let victim = (0..self.voices.len())
+                    .min_by(|a, b| {
+                        let (a, b) = (&self.voices[*a], &self.voices[*b]);
+                        let key = |v: &Voice<T>| match stealing {
+                            VoiceStealing::Oldest => 0.0,
+                            VoiceStealing::Quietest => v.level,
+                            VoiceStealing::Lowest => v.crossing_rate,
+                        };
+                        key(a)
+                            .partial_cmp(&key(b))
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                            .then(a.started.cmp(&b.started))
+                    })
+                    .unwrap();
+                self.voices.remove(victim);
+            }
         }
     }
 }
@@ -41,40 +141,170 @@ pub fn sequence_from_iterator<'a, T, I: IntoIterator<Item = (Duration, Value<'a,
     FancySequence::new(iterator, |iterator| iterator.next())
 }
 
-impl<'a, S, T: Default + Add<Output = T> + Clone> ValueNode for FancySequence<'a, S, T> {
+impl<'a, S, T: Frame + Add<Output = T>> ValueNode for FancySequence<'a, S, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
         if self.samples_remaining == 0 {
+            for voice in &mut self.voices {
+                voice.ended = true;
+            }
             let note = (self.generator)(&mut self.state);
-            if note.is_some() {
-                let (duration, note) = note.unwrap();
-                self.current_notes[0].replace(note);
-                self.current_notes.rotate_left(1);
+            if let Some((duration, mut note)) = note {
+                self.steal_voice();
+                note.prepare(self.max_block_size);
+                self.voices.push(Voice::new(note, self.notes_started));
+                self.notes_started += 1;
                 self.samples_remaining = (duration.as_secs_f64() * env.sample_rate as f64) as usize;
             } else {
-                self.samples_remaining = std::usize::MAX;
+                self.exhausted = true;
+                self.samples_remaining = usize::MAX;
             }
         }
         let remaining = self.samples_remaining.min(samples);
-        let mut result: Vec<Self::T> = (0..remaining).map(|_| Self::T::default()).collect();
-        let mut is_first = true;
-        for note in &mut self.current_notes {
-            if let Some(note) = note {
-                note.fill_buffer(env, &mut result, remaining);
-                if is_first {
-                    buffer[0..remaining].clone_from_slice(&result);
-                    is_first = false;
-                } else {
-                    for i in 0..remaining {
-                        let cur = buffer[i].clone();
-                        buffer[i] = cur + result[i].clone();
-                    }
-                }
+        for sample in &mut buffer[0..remaining] {
+            *sample = T::default();
+        }
+        let result = scratch(&mut self.result, remaining);
+        for voice in &mut self.voices {
+            voice.value.fill_buffer(env, result, remaining);
+            voice.measure(result);
+            for i in 0..remaining {
+                buffer[i] = buffer[i] + result[i];
             }
         }
+        let timeout = self
+            .silence_timeout
+            .map(|t| (t.as_secs_f64() * env.sample_rate as f64) as usize);
+        self.voices.retain(|voice| {
+            let timed_out = timeout.is_some_and(|t| voice.ended && voice.silent_samples >= t);
+            !(voice.value.is_finished() || timed_out)
+        });
+
         self.samples_remaining -= remaining;
         if remaining < samples {
             self.fill_buffer(env, &mut buffer[remaining..], samples - remaining);
         }
     }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        self.max_block_size = max_block_size;
+        scratch(&mut self.result, max_block_size);
+        for voice in &mut self.voices {
+            voice.value.prepare(max_block_size);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.exhausted && self.voices.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A square wave that swaps sign every `half_period` samples and never finishes
+    struct Square {
+        amplitude: f64,
+        half_period: usize,
+        position: usize,
+    }
+
+    impl Square {
+        fn at(&self, n: usize) -> f64 {
+            if (n / self.half_period).is_multiple_of(2) {
+                self.amplitude
+            } else {
+                -self.amplitude
+            }
+        }
+    }
+
+    impl ValueNode for Square {
+        type T = f64;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for sample in &mut buffer[0..samples] {
+                *sample = self.at(self.position);
+                self.position += 1;
+            }
+        }
+    }
+
+    fn square(amplitude: f64, half_period: usize) -> Square {
+        Square {
+            amplitude,
+            half_period,
+            position: 0,
+        }
+    }
+
+    // Three 10 sample notes into two voices, returning the last 10 samples
+    fn third_note(stealing: VoiceStealing, notes: Vec<Square>) -> Vec<f64> {
+        let notes = notes
+            .into_iter()
+            .map(|note| (Duration::from_millis(10), Value::from(note)));
+        let mut sequence: Value<f64> = sequence_from_iterator(notes)
+            .voice_limit(2, stealing)
+            .into();
+        let env = Env::new(1000);
+        let mut output = vec![0.0; 30];
+        for block in output.chunks_mut(5) {
+            sequence.fill_buffer(&env, block, 5);
+        }
+        output.split_off(20)
+    }
+
+    fn sum(voices: &[(&Square, usize)], n: usize) -> f64 {
+        voices.iter().map(|(v, start)| v.at(n - start)).sum()
+    }
+
+    #[test]
+    fn oldest_voice_is_stolen() {
+        let output = third_note(
+            VoiceStealing::Oldest,
+            vec![square(1.0, 3), square(2.0, 3), square(4.0, 3)],
+        );
+        let (b, c) = (square(2.0, 3), square(4.0, 3));
+        for (n, v) in (20..).zip(&output) {
+            assert_eq!(*v, sum(&[(&b, 10), (&c, 20)], n));
+        }
+    }
+
+    #[test]
+    fn quietest_voice_is_stolen() {
+        let output = third_note(
+            VoiceStealing::Quietest,
+            vec![square(1.0, 3), square(0.5, 3), square(4.0, 3)],
+        );
+        let (a, c) = (square(1.0, 3), square(4.0, 3));
+        for (n, v) in (20..).zip(&output) {
+            assert_eq!(*v, sum(&[(&a, 0), (&c, 20)], n));
+        }
+    }
+
+    #[test]
+    fn lowest_voice_is_stolen() {
+        let output = third_note(
+            VoiceStealing::Lowest,
+            vec![square(1.0, 1), square(2.0, 100), square(4.0, 1)],
+        );
+        let (a, c) = (square(1.0, 1), square(4.0, 1));
+        for (n, v) in (20..).zip(&output) {
+            assert_eq!(*v, sum(&[(&a, 0), (&c, 20)], n));
+        }
+    }
+
+    #[test]
+    fn silent_voices_are_dropped_after_the_timeout() {
+        let notes = vec![(Duration::from_millis(10), Value::from(0.0))];
+        let mut sequence = sequence_from_iterator(notes);
+        let env = Env::new(1000);
+        let mut buffer = vec![0.0; 100];
+        for _ in 0..9 {
+            sequence.fill_buffer(&env, &mut buffer, 100);
+        }
+        assert!(!sequence.is_finished());
+        sequence.fill_buffer(&env, &mut buffer, 100);
+        assert!(sequence.is_finished());
+    }
 }
@@ -0,0 +1,253 @@
+mod stft;
+mod window;
+pub use stft::*;
+pub use window::*;
+
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+
+// Radix-2 complex FFT with the twiddle factors and bit reversal worked out up front so
+// transforms don't allocate.
+pub struct Fft {
+    size: usize,
+    twiddles: Vec<Complex64>,
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    // `size` must be a power of two
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let bits = size.trailing_zeros();
+        let twiddles = (0..size / 2)
+            .map(|i| Complex64::from_polar(&1.0, &(-2.0 * PI * i as f64 / size as f64)))
+            .collect();
+        let bit_reverse = (0..size)
+            .map(|i| {
+                if bits == 0 {
+                    0
+                } else {
+                    i.reverse_bits() >> (std::mem::size_of::<usize>() as u32 * 8 - bits)
+                }
+            })
+            .collect();
+        Self {
+            size,
+            twiddles,
+            bit_reverse,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn forward(&self, data: &mut [Complex64]) {
+        self.transform(data, false);
+    }
+
+    // Inverse transform, scaled so that forward then inverse gives back the input
+    pub fn inverse(&self, data: &mut [Complex64]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size as f64;
+        for v in data.iter_mut() {
+            *v *= scale;
+        }
+    }
+
+    fn transform(&self, data: &mut [Complex64], inverse: bool) {
+        assert_eq!(data.len(), self.size);
+        for i in 0..self.size {
+            let j = self.bit_reverse[i];
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= self.size {
+            let step = self.size / len;
+            for start in (0..self.size).step_by(len) {
+                for k in 0..len / 2 {
+                    let mut w = self.twiddles[k * step];
+                    if inverse {
+                        w = w.conj();
+                    }
+                    let a = data[start + k];
+                    let b = data[start + k + len / 2] * w;
+                    data[start + k] = a + b;
+                    data[start + k + len / 2] = a - b;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+// FFT of real signals, done as a half size complex FFT. Only the non-negative frequency
+// bins, size / 2 + 1 of them, are produced or expected.
+pub struct RealFft {
+    size: usize,
+    fft: Fft,
+    twiddles: Vec<Complex64>,
+    scratch: Vec<Complex64>,
+}
+
+impl RealFft {
+    // `size` must be a power of two and at least 2
+    pub fn new(size: usize) -> Self {
+        assert!(
+            size >= 2 && size.is_power_of_two(),
+            "FFT size must be a power of two"
+        );
+        let half = size / 2;
+        Self {
+            size,
+            fft: Fft::new(half),
+            twiddles: (0..half)
+                .map(|k| Complex64::from_polar(&1.0, &(-2.0 * PI * k as f64 / size as f64)))
+                .collect(),
+            scratch: vec![Complex64::new(0.0, 0.0); half],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn bins(&self) -> usize {
+        self.size / 2 + 1
+    }
+
+    pub fn forward(&mut self, input: &[f64], output: &mut [Complex64]) {
+        assert_eq!(input.len(), self.size);
+        assert_eq!(output.len(), self.bins());
+        let half = self.size / 2;
+        for (k, z) in self.scratch.iter_mut().enumerate() {
+            *z = Complex64::new(input[2 * k], input[2 * k + 1]);
+        }
+        self.fft.forward(&mut self.scratch);
+        for (k, out) in output.iter_mut().enumerate() {
+            let z = self.scratch[k % half];
+            let mirror = self.scratch[(half - k) % half].conj();
+            let even = (z + mirror) * 0.5;
+            let odd = (z - mirror) * Complex64::new(0.0, -0.5);
+            let twiddle = if k == half {
+                Complex64::new(-1.0, 0.0)
+            } else {
+                self.twiddles[k]
+            };
+            *out = even + twiddle * odd;
+        }
+    }
+
+    // Inverse transform, scaled so that forward then inverse gives back the input
+    pub fn inverse(&mut self, input: &[Complex64], output: &mut [f64]) {
+        assert_eq!(input.len(), self.bins());
+        assert_eq!(output.len(), self.size);
+        let half = self.size / 2;
+        for k in 0..half {
+            let x = input[k];
+            let mirror = input[half - k].conj();
+            let even = (x + mirror) * 0.5;
+            let odd = (x - mirror) * 0.5 * self.twiddles[k].conj();
+            self.scratch[k] = even + Complex64::new(0.0, 1.0) * odd;
+        }
+        self.fft.inverse(&mut self.scratch);
+        for (k, z) in self.scratch.iter().enumerate() {
+            output[2 * k] = z.re;
+            output[2 * k + 1] = z.im;
+        }
+    }
+}
+
+pub fn magnitudes(spectrum: &[Complex64]) -> Vec<f64> {
+    spectrum.iter().map(|bin| bin.norm()).collect()
+}
+
+pub fn phases(spectrum: &[Complex64]) -> Vec<f64> {
+    spectrum.iter().map(|bin| bin.arg()).collect()
+}
+
+pub fn from_polar(magnitudes: &[f64], phases: &[f64]) -> Vec<Complex64> {
+    magnitudes
+        .iter()
+        .zip(phases)
+        .map(|(m, p)| Complex64::from_polar(m, p))
+        .collect()
+}
+
+// Centre frequency of a bin in a transform of `size` samples
+pub fn bin_frequency(bin: usize, size: usize, sample_rate: u32) -> f64 {
+    bin as f64 * sample_rate as f64 / size as f64
+}
+
+pub fn to_db(magnitude: f64) -> f64 {
+    20.0 * magnitude.max(1e-12).log10()
+}
+
+pub fn from_db(db: f64) -> f64 {
+    10.0f64.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|n| {
+                let t = n as f64;
+                (0.3 * t).sin() + 0.5 * (1.7 * t + 0.2).cos() + 0.1 * (n % 7) as f64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fft_round_trip() {
+        for &size in &[1, 2, 8, 256] {
+            let fft = Fft::new(size);
+            let input: Vec<_> = signal(size * 2)
+                .chunks(2)
+                .map(|c| Complex64::new(c[0], c[1]))
+                .collect();
+            let mut data = input.clone();
+            fft.forward(&mut data);
+            fft.inverse(&mut data);
+            for (a, b) in data.iter().zip(&input) {
+                assert!((a - b).norm() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn real_fft_matches_dft() {
+        let size = 64;
+        let input = signal(size);
+        let mut output = vec![Complex64::new(0.0, 0.0); size / 2 + 1];
+        RealFft::new(size).forward(&input, &mut output);
+        for (k, bin) in output.iter().enumerate() {
+            let expected: Complex64 = input
+                .iter()
+                .enumerate()
+                .map(|(n, v)| Complex64::from_polar(v, &(-2.0 * PI * (k * n) as f64 / size as f64)))
+                .sum();
+            assert!((bin - expected).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn real_fft_round_trip() {
+        for &size in &[2, 4, 128] {
+            let mut fft = RealFft::new(size);
+            let input = signal(size);
+            let mut spectrum = vec![Complex64::new(0.0, 0.0); fft.bins()];
+            let mut output = vec![0.0; size];
+            fft.forward(&input, &mut spectrum);
+            fft.inverse(&spectrum, &mut output);
+            for (a, b) in output.iter().zip(&input) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use num::complex::Complex64;
+
+use crate::{
+    spectral::{RealFft, Window},
+    value::{scratch, Tail, Value, ValueNode},
+    Env,
+};
+
+// Short time Fourier transform. Frames of `size` samples are taken every `hop` samples and
+// windowed on the way in and again on the way out of the inverse transform, so the overlap
+// added result comes back to the original signal.
+pub struct Stft {
+    size: usize,
+    hop: usize,
+    window: Vec<f64>,
+    // Sum of the squared windows overlapping each position within a hop, to undo their gain
+    overlap: Vec<f64>,
+    fft: RealFft,
+    frame: Vec<f64>,
+}
+
+impl Stft {
+    // `size` must be a power of two and `hop` no larger than it
+    pub fn new(size: usize, hop: usize, window: Window) -> Self {
+        let hop = hop.max(1).min(size);
+        let window = window.coefficients(size);
+        let overlap = (0..hop)
+            .map(|j| {
+                (j..size)
+                    .step_by(hop)
+                    .map(|n| window[n] * window[n])
+                    .sum::<f64>()
+                    .max(1e-9)
+            })
+            .collect();
+        Self {
+            size,
+            hop,
+            window,
+            overlap,
+            fft: RealFft::new(size),
+            frame: vec![0.0; size],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    pub fn bins(&self) -> usize {
+        self.fft.bins()
+    }
+
+    // Windows and transforms `size` samples into `bins` values
+    pub fn analyze_frame(&mut self, samples: &[f64], spectrum: &mut [Complex64]) {
+        for ((f, s), w) in self.frame.iter_mut().zip(samples).zip(&self.window) {
+            *f = s * w;
+        }
+        self.fft.forward(&self.frame, spectrum);
+    }
+
+    // Transforms back and adds the windowed result into `output`, which must hold at least
+    // `size` samples. Everything added is already scaled for the overlap.
+    pub fn synthesize_frame(&mut self, spectrum: &[Complex64], output: &mut [f64]) {
+        self.fft.inverse(spectrum, &mut self.frame);
+        for (n, o) in output.iter_mut().take(self.size).enumerate() {
+            *o += self.frame[n] * self.window[n] / self.overlap[n % self.hop];
+        }
+    }
+
+    // Splits a whole signal into frames. The signal is padded with silence on both ends so
+    // every sample is covered by as many frames as any other.
+    pub fn analyze(&mut self, signal: &[f64]) -> Vec<Vec<Complex64>> {
+        let lead = self.size - self.hop;
+        let mut padded = vec![0.0; lead];
+        padded.extend_from_slice(signal);
+        let frames = (signal.len() + lead).div_ceil(self.hop);
+        padded.resize(frames * self.hop + self.size, 0.0);
+        (0..frames)
+            .map(|f| {
+                let mut spectrum = vec![Complex64::new(0.0, 0.0); self.bins()];
+                let start = f * self.hop;
+                self.analyze_frame(&padded[start..start + self.size], &mut spectrum);
+                spectrum
+            })
+            .collect()
+    }
+
+    // Overlap-adds frames from `analyze` (or altered versions of them) back into `len`
+    // samples of signal
+    pub fn synthesize(&mut self, frames: &[Vec<Complex64>], len: usize) -> Vec<f64> {
+        let lead = self.size - self.hop;
+        let mut output = vec![0.0; frames.len() * self.hop + self.size];
+        for (f, spectrum) in frames.iter().enumerate() {
+            let start = f * self.hop;
+            self.synthesize_frame(spectrum, &mut output[start..]);
+        }
+        output.into_iter().skip(lead).take(len).collect()
+    }
+}
+
+// Runs a closure over the spectrum of every STFT frame of its input and resynthesizes the
+// result. Output lags the input by `size` samples.
+pub struct SpectralProcessor<'a, F> {
+    input: Value<'a, f64>,
+    process: F,
+    stft: Stft,
+
+    analysis: Vec<f64>,
+    accumulator: Vec<f64>,
+    ready: Vec<f64>,
+    spectrum: Vec<Complex64>,
+    position: usize,
+    input_buffer: Vec<f64>,
+    tail: Tail,
+}
+
+impl<'a, F: FnMut(&mut [Complex64])> SpectralProcessor<'a, F> {
+    pub fn new(input: impl Into<Value<'a, f64>>, stft: Stft, process: F) -> Self {
+        let (size, hop, bins) = (stft.size(), stft.hop(), stft.bins());
+        Self {
+            input: input.into(),
+            process,
+            stft,
+
+            analysis: vec![0.0; size],
+            accumulator: vec![0.0; size],
+            ready: vec![0.0; hop],
+            spectrum: vec![Complex64::new(0.0, 0.0); bins],
+            position: 0,
+            input_buffer: vec![],
+            tail: Tail::default(),
+        }
+    }
+}
+
+impl<'a, F: FnMut(&mut [Complex64])> ValueNode for SpectralProcessor<'a, F> {
+    type T = f64;
+    fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
+        let input = scratch(&mut self.input_buffer, samples);
+        self.input.fill_buffer(env, input, samples);
+        let (size, hop) = (self.stft.size(), self.stft.hop());
+        for (i, out) in buffer[0..samples].iter_mut().enumerate() {
+            *out = self.ready[self.position];
+            self.analysis[size - hop + self.position] = self.input_buffer[i];
+            self.position += 1;
+            if self.position == hop {
+                self.position = 0;
+                self.stft.analyze_frame(&self.analysis, &mut self.spectrum);
+                (self.process)(&mut self.spectrum);
+                self.stft
+                    .synthesize_frame(&self.spectrum, &mut self.accumulator);
+                self.ready.copy_from_slice(&self.accumulator[0..hop]);
+                self.accumulator.copy_within(hop.., 0);
+                for v in &mut self.accumulator[size - hop..] {
+                    *v = 0.0;
+                }
+                self.analysis.copy_within(hop.., 0);
+            }
+        }
+        self.tail
+            .update(self.input.is_finished(), &buffer[0..samples], size);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.input_buffer, max_block_size);
+        self.input.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.tail.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOWS: [Window; 5] = [
+        Window::Rectangular,
+        Window::Hann,
+        Window::Hamming,
+        Window::Blackman,
+        Window::BlackmanHarris,
+    ];
+
+    fn signal(len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|n| (0.05 * n as f64).sin() + 0.3 * (0.71 * n as f64).cos())
+            .collect()
+    }
+
+    #[test]
+    fn overlap_add_reconstructs_the_signal() {
+        let input = signal(1000);
+        for &window in &WINDOWS {
+            for &hop in &[8, 16, 32] {
+                let mut stft = Stft::new(64, hop, window);
+                let frames = stft.analyze(&input);
+                let output = stft.synthesize(&frames, input.len());
+                assert_eq!(output.len(), input.len());
+                for (a, b) in output.iter().zip(&input) {
+                    assert!((a - b).abs() < 1e-9, "{:?} hop {}", window, hop);
+                }
+            }
+        }
+    }
+
+    struct Samples(Vec<f64>, usize);
+
+    impl ValueNode for Samples {
+        type T = f64;
+        fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
+            for b in &mut buffer[0..samples] {
+                *b = self.0.get(self.1).cloned().unwrap_or(0.0);
+                self.1 += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn processor_passes_the_signal_through_delayed_by_size() {
+        let input = signal(1000);
+        for &window in &WINDOWS {
+            let mut processor: Value<f64> = SpectralProcessor::new(
+                Samples(input.clone(), 0),
+                Stft::new(64, 16, window),
+                |_: &mut [Complex64]| {},
+            )
+            .into();
+            let env = Env::new(44100);
+            let mut output = vec![0.0; 1100];
+            for block in output.chunks_mut(100) {
+                processor.fill_buffer(&env, block, 100);
+            }
+            assert!(output[0..64].iter().all(|v| v.abs() < 1e-9));
+            for (a, b) in output[64..].iter().zip(&input) {
+                assert!((a - b).abs() < 1e-9, "{:?}", window);
+            }
+        }
+    }
+}
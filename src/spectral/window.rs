@@ -0,0 +1,31 @@
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+    BlackmanHarris,
+}
+
+impl Window {
+    // Periodic form of the window, which is what overlap-add wants
+    pub fn coefficients(self, size: usize) -> Vec<f64> {
+        (0..size)
+            .map(|n| {
+                let x = 2.0 * PI * n as f64 / size as f64;
+                match self {
+                    Window::Rectangular => 1.0,
+                    Window::Hann => 0.5 - 0.5 * x.cos(),
+                    Window::Hamming => 0.54 - 0.46 * x.cos(),
+                    Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                    Window::BlackmanHarris => {
+                        0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos()
+                            - 0.01168 * (3.0 * x).cos()
+                    }
+                }
+            })
+            .collect()
+    }
+}
@@ -13,6 +13,56 @@ use super::Env;
 pub trait ValueNode {
     type T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize);
+
+    // Called before rendering with the largest block that will be requested so nodes can
+    // size their work buffers up front instead of allocating inside fill_buffer.
+    fn prepare(&mut self, _max_block_size: usize) {}
+
+    // True once the node will only ever produce T::default() again, which lets sequences drop
+    // voices that have played out and renders stop when the whole graph has gone quiet.
+    fn is_finished(&self) -> bool {
+        false
+    }
+}
+
+// Output quieter than this counts as silence
+pub const SILENCE: f64 = 0.00001;
+
+pub fn is_silent<T: Frame>(buffer: &[T]) -> bool {
+    buffer
+        .iter()
+        .all(|frame| (0..T::CHANNELS).all(|c| frame.channel(c).abs() < SILENCE))
+}
+
+// Lets nodes that keep ringing after their input stops (filters, feedback delays) report
+// being finished once their output has stayed silent for long enough.
+#[derive(Clone, Debug, Default)]
+pub struct Tail {
+    silent_samples: usize,
+    finished: bool,
+}
+
+impl Tail {
+    pub fn update<T: Frame>(&mut self, input_finished: bool, output: &[T], required: usize) {
+        if input_finished && is_silent(output) {
+            self.silent_samples += output.len();
+        } else {
+            self.silent_samples = 0;
+        }
+        self.finished = input_finished && self.silent_samples >= required;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+// Grows a node's work buffer if needed and returns the first `samples` entries of it.
+pub fn scratch<T: Default>(buffer: &mut Vec<T>, samples: usize) -> &mut [T] {
+    if buffer.len() < samples {
+        buffer.resize_with(samples, T::default);
+    }
+    &mut buffer[0..samples]
 }
 
 pub struct Value<'a, T>(Box<dyn ValueNode<T = T> + 'a>);
@@ -26,29 +76,47 @@ impl<'a, T: Default> Value<'a, T> {
     pub fn fill_buffer(&mut self, env: &Env, buffer: &mut [T], samples: usize) {
         self.0.fill_buffer(env, buffer, samples);
     }
+
+    pub fn prepare(&mut self, max_block_size: usize) {
+        self.0.prepare(max_block_size);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
 }
 
-pub struct ValueConverter<'a, A, B>(Value<'a, A>, PhantomData<B>);
+pub struct ValueConverter<'a, A, B>(Value<'a, A>, Vec<A>, PhantomData<B>);
 impl<'a, A, B> ValueConverter<'a, A, B> {
     pub fn new(other: impl Into<Value<'a, A>>) -> Self {
-        ValueConverter(other.into(), PhantomData)
+        ValueConverter(other.into(), vec![], PhantomData)
     }
 }
 
 impl<'a, A: Copy + Default, B: From<A>> ValueNode for ValueConverter<'a, A, B> {
     type T = B;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut pre_buffer: Vec<A> = (0..samples).map(|_| A::default()).collect();
-        self.0.fill_buffer(env, &mut pre_buffer, samples);
+        let pre_buffer = scratch(&mut self.1, samples);
+        self.0.fill_buffer(env, pre_buffer, samples);
         for (i, s) in pre_buffer.iter().enumerate() {
             buffer[i] = (*s).into();
         }
     }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.1, max_block_size);
+        self.0.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
 }
 
 struct CacheValueState<T> {
     trigger: (Duration, usize),
     cached_value: Option<Vec<T>>,
+    prepared_block_size: usize,
 }
 
 pub struct CacheValue<'a, T> {
@@ -72,6 +140,7 @@ impl<'a, T> CacheValue<'a, T> {
             state: Rc::new(RefCell::new(CacheValueState {
                 trigger: (Duration::new(0, 0), 0),
                 cached_value: None,
+                prepared_block_size: 0,
             })),
         }
     }
@@ -81,14 +150,35 @@ impl<'a, T: Clone + Default> ValueNode for CacheValue<'a, T> {
     type T = T;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
         let mut state = self.state.borrow_mut();
-        if state.cached_value.is_some() && state.trigger == (env.time, samples) {
-            buffer[0..samples].clone_from_slice(state.cached_value.as_ref().unwrap());
-        } else {
-            self.value.borrow_mut().fill_buffer(env, buffer, samples);
-            state.cached_value.replace(buffer[0..samples].to_vec());
-            state.trigger = (env.time, samples);
+        if state.trigger == (env.time, samples) {
+            if let Some(cached_value) = &state.cached_value {
+                buffer[0..samples].clone_from_slice(cached_value);
+                return;
+            }
+        }
+        self.value.borrow_mut().fill_buffer(env, buffer, samples);
+        let cached_value = state.cached_value.get_or_insert_with(Vec::new);
+        cached_value.clear();
+        cached_value.extend_from_slice(&buffer[0..samples]);
+        state.trigger = (env.time, samples);
+    }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        // Every clone shares the same state so only the first one needs to do the work
+        let mut state = self.state.borrow_mut();
+        if state.prepared_block_size < max_block_size {
+            state.prepared_block_size = max_block_size;
+            state
+                .cached_value
+                .get_or_insert_with(Vec::new)
+                .reserve(max_block_size);
+            self.value.borrow_mut().prepare(max_block_size);
         }
     }
+
+    fn is_finished(&self) -> bool {
+        self.value.borrow().is_finished()
+    }
 }
 
 macro_rules! value_node_impl_for_numerics {
@@ -3,7 +3,7 @@ use std::ops::{Neg, Not};
 
 use crate::{
     effect::Delay,
-    value::{CacheValue, Value, ValueNode},
+    value::{scratch, CacheValue, Value, ValueNode},
     Env,
 };
 
@@ -28,28 +28,91 @@ impl<T: Clone> From<T> for MultiSample<T> {
         MultiSample(other.clone(), other.clone())
     }
 }
+pub trait Frame: Copy + Default {
+    const CHANNELS: usize;
+    fn channel(&self, idx: usize) -> f64;
+    fn from_channels(channels: &[f64]) -> Self;
+}
+impl Frame for f64 {
+    const CHANNELS: usize = 1;
+    fn channel(&self, _idx: usize) -> f64 {
+        *self
+    }
+    fn from_channels(channels: &[f64]) -> Self {
+        if channels.is_empty() {
+            0.0
+        } else {
+            channels.iter().sum::<f64>() / channels.len() as f64
+        }
+    }
+}
+impl Frame for MultiSample<f64> {
+    const CHANNELS: usize = 2;
+    fn channel(&self, idx: usize) -> f64 {
+        if idx == 0 {
+            self.0
+        } else {
+            self.1
+        }
+    }
+    fn from_channels(channels: &[f64]) -> Self {
+        match channels {
+            [] => MultiSample(0.0, 0.0),
+            [v] => MultiSample(*v, *v),
+            [l, r, ..] => MultiSample(*l, *r),
+        }
+    }
+}
+
 impl ValueNode for MultiSample<f64> {
     type T = MultiSample<f64>;
     fn fill_buffer(&mut self, _env: &Env, buffer: &mut [Self::T], samples: usize) {
-        for i in 0..samples {
-            buffer[i] = *self;
+        for sample in &mut buffer[0..samples] {
+            *sample = *self;
         }
     }
 }
 
-pub struct Bundler<'a, T>(pub Value<'a, T>, pub Value<'a, T>);
+pub struct Bundler<'a, T> {
+    left: Value<'a, T>,
+    right: Value<'a, T>,
+    left_buffer: Vec<T>,
+    right_buffer: Vec<T>,
+}
+
+impl<'a, T> Bundler<'a, T> {
+    pub fn new(left: impl Into<Value<'a, T>>, right: impl Into<Value<'a, T>>) -> Self {
+        Bundler {
+            left: left.into(),
+            right: right.into(),
+            left_buffer: vec![],
+            right_buffer: vec![],
+        }
+    }
+}
 
 impl<'a, T: Zero + Default + Copy> ValueNode for Bundler<'a, T> {
     type T = MultiSample<T>;
     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-        let mut a: Vec<T> = (0..samples).map(|_| T::zero()).collect();
-        self.0.fill_buffer(env, &mut a, samples);
-        let mut b: Vec<T> = (0..samples).map(|_| T::zero()).collect();
-        self.1.fill_buffer(env, &mut b, samples);
-        for (i, (a, b)) in a.iter().zip(&b).enumerate() {
+        let a = scratch(&mut self.left_buffer, samples);
+        self.left.fill_buffer(env, a, samples);
+        let b = scratch(&mut self.right_buffer, samples);
+        self.right.fill_buffer(env, b, samples);
+        for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
             buffer[i] = MultiSample(*a, *b);
         }
     }
+
+    fn prepare(&mut self, max_block_size: usize) {
+        scratch(&mut self.left_buffer, max_block_size);
+        scratch(&mut self.right_buffer, max_block_size);
+        self.left.prepare(max_block_size);
+        self.right.prepare(max_block_size);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.left.is_finished() && self.right.is_finished()
+    }
 }
 
 pub fn hass_shift<'a, T: Clone + Copy + Default + Zero + 'a>(
@@ -60,9 +123,9 @@ pub fn hass_shift<'a, T: Clone + Copy + Default + Zero + 'a>(
     let delayed = Delay::new(input.clone(), shift.abs());
 
     if shift > 0.0 {
-        Bundler(input.into(), delayed.into())
+        Bundler::new(input, delayed)
     } else {
-        Bundler(delayed.into(), input.into())
+        Bundler::new(delayed, input)
     }
     .into()
 }
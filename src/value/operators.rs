@@ -1,30 +1,55 @@
 macro_rules! value_binary_operator {
-    ( $operator_name:ident, $operator_assign_name:ident, $operator_method:ident, $operator_assign_method:ident, $operation:tt, $( $numeric:ident ),* ) =>  {
+    ( $operator_name:ident, $operator_assign_name:ident, $operator_method:ident, $operator_assign_method:ident, $operation:tt, $finished:expr, $( $numeric:ident ),* ) =>  {
         #[allow(non_snake_case)]
         mod $operator_name {
             use std::ops::{$operator_name,};
             use crate::{
-                value::{ValueNode, Value},
+                value::{scratch, ValueNode, Value},
                 Env,
             };
             struct Operator<'a, T> {
                 a: Value<'a, T>,
                 b: Value<'a, T>,
+                a_buffer: Vec<T>,
+                b_buffer: Vec<T>,
+            }
+
+            impl<'a, T> Operator<'a, T> {
+                fn new(a: Value<'a, T>, b: Value<'a, T>) -> Self {
+                    Operator {
+                        a,
+                        b,
+                        a_buffer: vec![],
+                        b_buffer: vec![],
+                    }
+                }
             }
 
             impl<'a, T: Default + Clone> ValueNode for Operator<'a, T>
                 where T: $operator_name<Output = T> + 'a {
                     type T = T;
                     fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-                        let mut a: Vec<Self::T> = (0..samples).map(|_| Self::T::default()).collect();
-                        self.a.fill_buffer(env, &mut a, samples);
-                        let mut b: Vec<Self::T> = (0..samples).map(|_| Self::T::default()).collect();
-                        self.b.fill_buffer(env, &mut b, samples);
+                        let a = scratch(&mut self.a_buffer, samples);
+                        self.a.fill_buffer(env, a, samples);
+                        let b = scratch(&mut self.b_buffer, samples);
+                        self.b.fill_buffer(env, b, samples);
 
                         for i in 0..samples {
                             buffer[i] = a[i].clone() $operation b[i].clone();
                         }
                     }
+
+                    fn prepare(&mut self, max_block_size: usize) {
+                        scratch(&mut self.a_buffer, max_block_size);
+                        scratch(&mut self.b_buffer, max_block_size);
+                        self.a.prepare(max_block_size);
+                        self.b.prepare(max_block_size);
+                    }
+
+                    fn is_finished(&self) -> bool {
+                        let finished: fn(bool, bool) -> bool = $finished;
+                        finished(self.a.is_finished(), self.b.is_finished())
+                    }
             }
 
             impl<'a, T: $operator_name<Output = T> + Default + Clone + 'a, D: Into<Value<'a, T>>> $operator_name<D> for Value<'a, T> {
@@ -32,10 +57,7 @@ macro_rules! value_binary_operator {
 
                 #[inline]
                 fn $operator_method(self, other: D) -> Self::Output {
-                    Operator {
-                        a: self,
-                        b: other.into(),
-                    }.into()
+                    Operator::new(self, other.into()).into()
                 }
             }
 
@@ -45,10 +67,7 @@ macro_rules! value_binary_operator {
 
                 #[inline]
                 fn $operator_method(self, other: Value<'a, $numeric>) -> Self::Output {
-                    Operator {
-                        a: self.into(),
-                        b: other,
-                    }.into()
+                    Operator::new(self.into(), other).into()
                 }
             }
             )*
@@ -56,39 +75,51 @@ macro_rules! value_binary_operator {
     }
 }
 
+// A finished node only produces T::default() from then on, so whether the result is finished
+// depends on the operator: a sum needs both sides to finish but a product only needs one.
 //TODO: I take in idents for the assign versions of the operators but I couldn't figure out how to
 //actually implement those traits so I'm not currently using them.
-value_binary_operator!(Add, AddAssign, add, add_assign, +, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
-value_binary_operator!(Sub, SubAssign, sub, sub_assign, -, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
-value_binary_operator!(Mul, MulAssign, mul, mul_assign, *, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
-value_binary_operator!(Div, DivAssign, div, div_assign, /, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
-value_binary_operator!(BitAnd, BitAndAssign, bitand, bitand_assign, &, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
-value_binary_operator!(BitOr, BitOrAssign, bitor, bitor_assign, |, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
-value_binary_operator!(BitXor, BitXorAssign, bitxor, bitxor_assign, ^, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
-value_binary_operator!(Rem, RemAssign, rem, rem_assign, %, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
-value_binary_operator!(Shl, ShlAssign, shl, shl_assign, <<, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128 );
-value_binary_operator!(Shr, ShrAssign, shr, shr_assign, >>, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
+value_binary_operator!(Add, AddAssign, add, add_assign, +, |a, b| a && b, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
+value_binary_operator!(Sub, SubAssign, sub, sub_assign, -, |a, b| a && b, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
+value_binary_operator!(Mul, MulAssign, mul, mul_assign, *, |a, b| a || b, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
+value_binary_operator!(Div, DivAssign, div, div_assign, /, |a, _| a, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
+value_binary_operator!(BitAnd, BitAndAssign, bitand, bitand_assign, &, |a, b| a || b, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
+value_binary_operator!(BitOr, BitOrAssign, bitor, bitor_assign, |, |a, b| a && b, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
+value_binary_operator!(BitXor, BitXorAssign, bitxor, bitxor_assign, ^, |a, b| a && b, bool, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
+value_binary_operator!(Rem, RemAssign, rem, rem_assign, %, |a, _| a, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128, f32, f64);
+value_binary_operator!(Shl, ShlAssign, shl, shl_assign, <<, |a, _| a, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128 );
+value_binary_operator!(Shr, ShrAssign, shr, shr_assign, >>, |a, _| a, usize, u8, u16, u32, u64, u128, isize, i8, i16, i32, i64, i128);
 
 //TODO: I should be able to do these with a similar macro to the binary operators but I was having
 //trouble with types. It only saves a few lines of boilerplate anyway.
 mod neg {
     use crate::{
-        value::{Value, ValueNode},
+        value::{scratch, Value, ValueNode},
         Env,
     };
     use std::ops::Neg;
     struct Operator<'a, T> {
         v: Value<'a, T>,
+        v_buffer: Vec<T>,
     }
     impl<'a, T: Default + Clone + Neg<Output = T>> ValueNode for Operator<'a, T> {
         type T = T;
         fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-            let mut v: Vec<Self::T> = (0..samples).map(|_| Self::T::default()).collect();
-            self.v.fill_buffer(env, &mut v, samples);
+            let v = scratch(&mut self.v_buffer, samples);
+            self.v.fill_buffer(env, v, samples);
             for i in 0..samples {
                 buffer[i] = -v[i].clone();
             }
         }
+
+        fn prepare(&mut self, max_block_size: usize) {
+            scratch(&mut self.v_buffer, max_block_size);
+            self.v.prepare(max_block_size);
+        }
+
+        fn is_finished(&self) -> bool {
+            self.v.is_finished()
+        }
     }
     impl<'a, T: Default + Clone + Neg<Output = T> + 'a> Neg for Value<'a, T>
     where
@@ -97,29 +128,39 @@ mod neg {
         type Output = Value<'a, T>;
 
         fn neg(self) -> Self::Output {
-            Operator { v: self }.into()
+            Operator {
+                v: self,
+                v_buffer: vec![],
+            }
+            .into()
         }
     }
 }
 
 mod not {
     use crate::{
-        value::{Value, ValueNode},
+        value::{scratch, Value, ValueNode},
         Env,
     };
     use std::ops::Not;
     struct Operator<'a, T> {
         v: Value<'a, T>,
+        v_buffer: Vec<T>,
     }
     impl<'a, T: Default + Clone + Not<Output = T>> ValueNode for Operator<'a, T> {
         type T = T;
         fn fill_buffer(&mut self, env: &Env, buffer: &mut [Self::T], samples: usize) {
-            let mut v: Vec<Self::T> = (0..samples).map(|_| Self::T::default()).collect();
-            self.v.fill_buffer(env, &mut v, samples);
+            let v = scratch(&mut self.v_buffer, samples);
+            self.v.fill_buffer(env, v, samples);
             for i in 0..samples {
                 buffer[i] = !v[i].clone();
             }
         }
+
+        fn prepare(&mut self, max_block_size: usize) {
+            scratch(&mut self.v_buffer, max_block_size);
+            self.v.prepare(max_block_size);
+        }
     }
     impl<'a, T: Default + Clone + Not<Output = T> + 'a> Not for Value<'a, T>
     where
@@ -128,7 +169,11 @@ mod not {
         type Output = Value<'a, T>;
 
         fn not(self) -> Self::Output {
-            Operator { v: self }.into()
+            Operator {
+                v: self,
+                v_buffer: vec![],
+            }
+            .into()
         }
     }
 }